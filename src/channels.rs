@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+use tracing::warn;
+
+use crate::config::{
+    OrbitTrapConfig, SampleConfig, SpectralConfig, Weighting, C_DENSITY_CHANNEL, ESCAPE_CHANNELS,
+};
+use crate::types::{EscapeError, NormalizedGrid};
+
+/// Float grids accumulated alongside the counts, by name
+/// Channels with one grid per layer are named `<name>_<layer>`
+pub type Channels = BTreeMap<String, NormalizedGrid>;
+
+/// The name of a channel's grid for a layer
+pub fn layer_channel(name: &str, layer: usize) -> String {
+    format!("{}_{}", name, layer)
+}
+
+/// Look up a channel, failing if the histogram did not record it
+pub fn channel<'a>(channels: &'a Channels, name: &str) -> Result<&'a NormalizedGrid, EscapeError> {
+    channels.get(name).ok_or_else(|| {
+        EscapeError::IncompatibleDrawConfig(format!("Histogram has no {} channel", name))
+    })
+}
+
+/// Sum channels into a total, other must have the same dimensions
+pub fn add_channels(total: &mut Channels, other: &Channels) {
+    for (name, grid) in other {
+        match total.get_mut(name) {
+            Some(total_grid) => total_grid.add(grid),
+            None => {
+                total.insert(name.clone(), grid.clone());
+            }
+        }
+    }
+}
+
+/// Sum the channels of several histograms
+/// Only channels that every histogram recorded are kept, since partial sums would be biased,
+/// and only if they have the same dimensions everywhere
+pub fn merge_channels(all: &[Channels]) -> Channels {
+    let mut result = Channels::new();
+    let Some((first, rest)) = all.split_first() else {
+        return result;
+    };
+    for (name, grid) in first {
+        let matches = |channels: &Channels| {
+            channels
+                .get(name)
+                .is_some_and(|other| other.dimensions() == grid.dimensions())
+        };
+        if rest.iter().all(matches) {
+            let mut total = grid.clone();
+            for channels in rest {
+                total.add(&channels[name]);
+            }
+            result.insert(name.clone(), total);
+        } else {
+            warn!(
+                "Dropping channel {}, not every histogram recorded it the same way",
+                name
+            );
+        }
+    }
+    result
+}
+
+/// The channels a worker records into, indexed in the order they were added
+/// Workers look up the indices they need once, to keep names out of the sampling loop
+#[derive(Debug, Clone, Default)]
+pub struct ChannelGrids {
+    names: Vec<String>,
+    grids: Vec<NormalizedGrid>,
+}
+
+impl ChannelGrids {
+    pub fn new(config: &SampleConfig) -> ChannelGrids {
+        let mut result = ChannelGrids::default();
+        for (name, width, height) in ChannelGrids::layout(config) {
+            result.names.push(name);
+            result.grids.push(NormalizedGrid::zero(width, height));
+        }
+        result
+    }
+
+    /// The number of cells a worker's channels hold, without allocating them
+    pub fn cell_count(config: &SampleConfig) -> usize {
+        ChannelGrids::layout(config)
+            .iter()
+            .map(|(_, width, height)| width * height)
+            .sum()
+    }
+
+    /// The name and dimensions of every channel the config records
+    fn layout(config: &SampleConfig) -> Vec<(String, usize, usize)> {
+        let mut result = Vec::new();
+        let (width, height) = (config.view.width, config.view.height);
+        let mut names = Vec::new();
+        if config.weighting.is_some() {
+            names.push(Weighting::CHANNEL);
+        }
+        if config.escape_channels {
+            names.extend(ESCAPE_CHANNELS);
+        }
+        if config
+            .orbit_traps
+            .as_ref()
+            .is_some_and(|traps| traps.distance_channel)
+        {
+            names.push(OrbitTrapConfig::CHANNEL);
+        }
+        for name in names {
+            for layer in 0..config.layer_count() {
+                result.push((layer_channel(name, layer), width, height));
+            }
+        }
+        if config.spectral.is_some() {
+            for name in SpectralConfig::CHANNELS {
+                result.push((name.to_string(), width, height));
+            }
+        }
+        if let Some(window) = &config.c_density {
+            result.push((C_DENSITY_CHANNEL.to_string(), window.width, window.height));
+        }
+        result
+    }
+
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|n| n == name)
+    }
+
+    pub fn add(&mut self, index: usize, value: f64, x: usize, y: usize) {
+        self.grids[index].add_value(value, x, y);
+    }
+
+    pub fn into_channels(self) -> Channels {
+        self.names.into_iter().zip(self.grids).collect()
+    }
+}
@@ -1,4 +1,5 @@
-use crate::types::Verbosity;
+use crate::timelapse::TimelapseSchedule;
+use crate::types::{MemorySize, Verbosity};
 use std::path::PathBuf;
 use structopt::StructOpt;
 
@@ -8,8 +9,14 @@ use structopt::StructOpt;
 pub enum CliOptions {
     Sample(SampleOptions),
     Draw(DrawOptions),
+    DrawTimelapse(DrawTimelapseOptions),
     Merge(MergeOptions),
     Report(ReportOptions),
+    Tune(TuneOptions),
+    Cutoffs(CutoffsOptions),
+    Coordinator(CoordinatorOptions),
+    Worker(WorkerOptions),
+    Serve(ServeOptions),
 }
 
 /// Sample the buddhabrot and create a histogram result
@@ -31,6 +38,67 @@ pub struct SampleOptions {
     #[structopt(short, long, parse(from_os_str))]
     pub output: PathBuf,
 
+    /// Have all workers record into one set of grids, instead of merging a copy per worker
+    #[structopt(long)]
+    pub shared_grids: bool,
+
+    /// Have workers record into tiled grids, which only allocate the regions orbits reach
+    #[structopt(long, conflicts_with = "shared-grids")]
+    pub tiled_grids: bool,
+
+    /// Seed for the workers random number generators, worker i uses seed + i
+    #[structopt(long)]
+    pub seed: Option<u64>,
+
+    /// Stop once this many samples have been taken, split evenly between workers
+    #[structopt(long)]
+    pub max_samples: Option<u64>,
+
+    /// Stop once this many metro instances have run, split evenly between workers
+    #[structopt(long)]
+    pub max_metro_instances: Option<u64>,
+
+    /// Collect per-chain diagnostics, written next to the output as <output>_diagnostics.json
+    #[structopt(long)]
+    pub diagnostics: bool,
+
+    /// Record a time-lapse of the histogram to this file, as deltas between frames
+    #[structopt(long, parse(from_os_str))]
+    pub timelapse: Option<PathBuf>,
+
+    /// When time-lapse frames are taken, either linear:<seconds> or geometric:<first>:<factor>
+    #[structopt(long, default_value = "geometric:10:1.5")]
+    pub timelapse_schedule: TimelapseSchedule,
+
+    /// Refuse to start sampling if the estimated peak memory exceeds this budget (e.g. 16G).
+    /// Shared grids are used instead if they would fit.
+    #[structopt(long)]
+    pub max_memory: Option<MemorySize>,
+
+    /// Seconds between progress reports, 0 disables them
+    #[structopt(long, default_value = "10")]
+    pub progress_interval: u64,
+
+    /// Write progress reports to this file as json lines, instead of logging them
+    #[structopt(long, parse(from_os_str))]
+    pub progress_file: Option<PathBuf>,
+
+    /// Serve worker metrics in the Prometheus text format on this address, at /metrics
+    #[structopt(long)]
+    pub metrics_addr: Option<String>,
+
+    /// Take a snapshot when this file appears, as well as on SIGUSR1. The file is removed.
+    #[structopt(long, parse(from_os_str))]
+    pub snapshot_trigger: Option<PathBuf>,
+
+    /// Path to write snapshots to, defaults to the output path with a _snapshot suffix
+    #[structopt(long, parse(from_os_str))]
+    pub snapshot_output: Option<PathBuf>,
+
+    /// Draw config for a preview png written along with each snapshot
+    #[structopt(long, parse(from_os_str))]
+    pub preview_draw_config: Option<PathBuf>,
+
     /// Logging verbosity
     #[structopt(short, long, default_value = "info")]
     pub verbosity: Verbosity,
@@ -65,6 +133,30 @@ pub struct DrawOptions {
     pub pretty_logging: bool,
 }
 
+/// Render each frame of a time-lapse to a numbered image
+#[derive(StructOpt, Debug)]
+pub struct DrawTimelapseOptions {
+    /// Path to the draw config file
+    #[structopt(short, long, parse(from_os_str))]
+    pub config: PathBuf,
+
+    /// Path to the time-lapse recorded while sampling
+    #[structopt(short, long, parse(from_os_str))]
+    pub timelapse: PathBuf,
+
+    /// Directory to write frame_00000.png, frame_00001.png, ... to
+    #[structopt(short, long, parse(from_os_str))]
+    pub output_dir: PathBuf,
+
+    /// Logging verbosity
+    #[structopt(short, long, default_value = "info")]
+    pub verbosity: Verbosity,
+
+    /// Use pretty logging
+    #[structopt(short, long)]
+    pub pretty_logging: bool,
+}
+
 /// Combine multiple compatible histogram results
 #[derive(StructOpt, Debug)]
 pub struct MergeOptions {
@@ -103,3 +195,170 @@ pub struct ReportOptions {
     #[structopt(short, long)]
     pub pretty_logging: bool,
 }
+
+/// Tune sampler parameters with short pilot runs, writing out a tuned sample config
+#[derive(StructOpt, Debug)]
+pub struct TuneOptions {
+    /// Path to the sample config to start from
+    #[structopt(short, long, parse(from_os_str))]
+    pub config: PathBuf,
+
+    /// Path to write the tuned sample config to
+    #[structopt(short, long, parse(from_os_str))]
+    pub output: PathBuf,
+
+    /// The number of workers for each pilot run, at least 2 are needed to estimate noise
+    #[structopt(short, long, default_value = "2")]
+    pub workers: usize,
+
+    /// How long each pilot run samples for (seconds)
+    #[structopt(short, long, default_value = "5")]
+    pub pilot_duration: u64,
+
+    /// How many times to go through every parameter
+    #[structopt(long, default_value = "1")]
+    pub passes: usize,
+
+    /// Seed pilot runs the same way, so they differ only by their parameters
+    #[structopt(long)]
+    pub seed: Option<u64>,
+
+    /// Logging verbosity
+    #[structopt(short, long, default_value = "info")]
+    pub verbosity: Verbosity,
+
+    /// Use pretty logging
+    #[structopt(long)]
+    pub pretty_logging: bool,
+}
+
+/// Propose cutoffs from the orbit-length spectrum of a pilot run, writing out a sample config
+#[derive(StructOpt, Debug)]
+pub struct CutoffsOptions {
+    /// Path to the sample config to start from
+    #[structopt(short, long, parse(from_os_str))]
+    pub config: PathBuf,
+
+    /// Path to write the sample config with proposed cutoffs to
+    #[structopt(short, long, parse(from_os_str))]
+    pub output: PathBuf,
+
+    /// The number of workers for the pilot run
+    #[structopt(short, long, default_value = "2")]
+    pub workers: usize,
+
+    /// How long the pilot run samples for (seconds)
+    #[structopt(short, long, default_value = "10")]
+    pub duration: u64,
+
+    /// The number of layers to propose, fewer may be proposed if the spectrum is narrow
+    #[structopt(short, long, default_value = "4")]
+    pub layers: usize,
+
+    /// The longest orbit to record, defaults to the largest cutoff in the config
+    #[structopt(short, long)]
+    pub max_iteration: Option<usize>,
+
+    /// Seed the pilot run, for reproducible proposals
+    #[structopt(long)]
+    pub seed: Option<u64>,
+
+    /// Logging verbosity
+    #[structopt(short, long, default_value = "info")]
+    pub verbosity: Verbosity,
+
+    /// Use pretty logging
+    #[structopt(long)]
+    pub pretty_logging: bool,
+}
+
+/// Coordinate sampling across remote workers, accumulating their results into one histogram
+#[derive(StructOpt, Debug)]
+pub struct CoordinatorOptions {
+    /// Path to the sample config file
+    #[structopt(short, long, parse(from_os_str))]
+    pub config: PathBuf,
+
+    /// Address to listen for workers on
+    /// Workers are not authenticated, so only listen beyond loopback on a trusted network
+    #[structopt(short, long, default_value = "127.0.0.1:7878")]
+    pub listen: String,
+
+    /// Set a timeout for the run (seconds)
+    #[structopt(short, long)]
+    pub duration: Option<u64>,
+
+    /// Path to store the accumulated histogram
+    #[structopt(short, long, parse(from_os_str))]
+    pub output: PathBuf,
+
+    /// How often the accumulated histogram is written to the output path (seconds)
+    #[structopt(long, default_value = "300")]
+    pub checkpoint_interval: u64,
+
+    /// How often workers send the counts they have recorded (seconds)
+    #[structopt(long, default_value = "10")]
+    pub delta_interval: u64,
+
+    /// First seed handed out to workers, each worker thread gets the next seed
+    #[structopt(long)]
+    pub seed: Option<u64>,
+
+    /// Logging verbosity
+    #[structopt(short, long, default_value = "info")]
+    pub verbosity: Verbosity,
+
+    /// Use pretty logging
+    #[structopt(short, long)]
+    pub pretty_logging: bool,
+}
+
+/// Sample on behalf of a coordinator
+#[derive(StructOpt, Debug)]
+pub struct WorkerOptions {
+    /// Address of the coordinator, host:port
+    #[structopt(long)]
+    pub connect: String,
+
+    /// The number of worker threads to spawn (more threads may be used)
+    #[structopt(short, long, default_value = "1")]
+    pub workers: usize,
+
+    /// Logging verbosity
+    #[structopt(short, long, default_value = "info")]
+    pub verbosity: Verbosity,
+
+    /// Use pretty logging
+    #[structopt(short, long)]
+    pub pretty_logging: bool,
+}
+
+/// Serve a REST API for queueing sample and draw jobs
+#[derive(StructOpt, Debug)]
+pub struct ServeOptions {
+    /// Address to serve the API on
+    #[structopt(short, long, default_value = "127.0.0.1:8080")]
+    pub listen: String,
+
+    /// The number of workers shared by all running jobs
+    #[structopt(short, long, default_value = "1")]
+    pub workers: usize,
+
+    /// Directory to store job results in
+    #[structopt(long, parse(from_os_str), default_value = "escape_jobs")]
+    pub data_dir: PathBuf,
+
+    /// Refuse sample jobs whose estimated peak memory exceeds this budget (e.g. 16G),
+    /// and hold queued jobs until running jobs leave room for them.
+    /// Shared grids are used instead if they would fit.
+    #[structopt(long)]
+    pub max_memory: Option<MemorySize>,
+
+    /// Logging verbosity
+    #[structopt(short, long, default_value = "info")]
+    pub verbosity: Verbosity,
+
+    /// Use pretty logging
+    #[structopt(short, long)]
+    pub pretty_logging: bool,
+}
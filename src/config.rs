@@ -1,5 +1,7 @@
 use crate::types::{Complex, EscapeError, EscapeResult};
 use serde::{Deserialize, Serialize};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 
 /// ViewConfig describes what region of the buddhabrot to render
 /// as well as the grid to use when creating histograms
@@ -15,8 +17,23 @@ pub struct ViewConfig {
 pub struct SampleConfig {
     /// When sampling, we record orbits in different histograms depending
     /// on the length of the orbit before it escapes.
+    /// Each layer takes the orbits longer than the previous cutoff, up to its own.
+    /// Either cutoffs or layers must be given
+    #[serde(default)]
     pub cutoffs: Vec<usize>,
 
+    /// Layers given as explicit [min, max] orbit lengths, used instead of cutoffs.
+    /// Ranges may overlap or leave gaps, orbits in a gap are not recorded.
+    /// Default value is None
+    #[serde(default)]
+    pub layers: Option<Vec<[usize; 2]>>,
+
+    /// Record an orbit to every layer whose range contains it,
+    /// instead of only the first one. Only matters for overlapping layers.
+    /// Default value is false
+    #[serde(default)]
+    pub cumulative_layers: bool,
+
     /// The region of the buddhabrot to render
     pub view: ViewConfig,
 
@@ -52,6 +69,15 @@ pub struct SampleConfig {
     #[serde(default = "SampleConfig::default_random_sample_prob")]
     pub random_sample_prob: f64,
 
+    /// Perturbations of the last good sample are between these radii,
+    /// relative to the width of the view (1 / zoom).
+    /// Default values are 0.0001 and 0.1
+    #[serde(default = "SampleConfig::default_min_mutation_radius")]
+    pub min_mutation_radius: f64,
+
+    #[serde(default = "SampleConfig::default_max_mutation_radius")]
+    pub max_mutation_radius: f64,
+
     /// We the norm at which we decide that an orbit has escaped.
     /// Default value is 2.0
     #[serde(default = "SampleConfig::default_norm_cutoff")]
@@ -66,6 +92,78 @@ pub struct SampleConfig {
     /// Default value is 5
     #[serde(default = "SampleConfig::default_outside_limit")]
     pub outside_limit: usize,
+
+    /// Skip this many iterations at the start of each orbit when recording it.
+    /// Default value is 0
+    #[serde(default)]
+    pub skip_iterations: usize,
+
+    /// Only record this many iterations at the end of each orbit, before it escapes.
+    /// Default value is None, recording the whole orbit
+    #[serde(default)]
+    pub last_iterations: Option<usize>,
+
+    /// Only record every k-th iteration of the recorded part of each orbit.
+    /// Default value is 1
+    #[serde(default = "SampleConfig::default_iteration_stride")]
+    pub iteration_stride: usize,
+
+    /// Record the conjugate of every orbit as well.
+    /// The buddhabrot is symmetric about the real axis when both the julia set
+    /// and mandelbrot params are real, so each orbit gives us a second one for free.
+    /// The view must be centered on the real axis, so conjugates land on the mirrored row.
+    /// Default value is false
+    #[serde(default)]
+    pub symmetry: bool,
+
+    /// Optionally run workers as parallel tempering ladders
+    /// Default value is None
+    #[serde(default)]
+    pub tempering: Option<TemperingConfig>,
+
+    /// Optionally stop sampling once the histogram has converged
+    /// Default value is None
+    #[serde(default)]
+    pub convergence: Option<ConvergenceConfig>,
+
+    /// Optionally accumulate a weight for every recorded point,
+    /// into a float channel per layer alongside the counts
+    /// Default value is None
+    #[serde(default)]
+    pub weighting: Option<Weighting>,
+
+    /// Accumulate the direction each orbit escapes in and a smooth escape time,
+    /// into float channels per layer alongside the counts
+    /// Default value is false
+    #[serde(default)]
+    pub escape_channels: bool,
+
+    /// Optionally map each orbit's length to a color and accumulate it,
+    /// into red, green and blue float channels alongside the counts
+    /// Default value is None
+    #[serde(default)]
+    pub spectral: Option<SpectralConfig>,
+
+    /// Optionally only record orbits that pass through an orbit trap
+    /// Default value is None
+    #[serde(default)]
+    pub orbit_traps: Option<OrbitTrapConfig>,
+
+    /// Optionally concentrate sampling where a grayscale image aligned with the view is bright
+    /// Default value is None
+    #[serde(default)]
+    pub mask: Option<MaskConfig>,
+
+    /// Optionally only sample points from a region of the complex plane
+    /// Default value is None, sampling within norm_cutoff of the origin
+    #[serde(default)]
+    pub c_domain: Option<CDomain>,
+
+    /// Optionally map where the orbits that reach the view start from, over a window of the
+    /// sampled plane. Each accepted sample adds its number of hits in the view to the
+    /// c_density channel. Default value is None
+    #[serde(default)]
+    pub c_density: Option<ViewConfig>,
 }
 
 impl SampleConfig {
@@ -89,10 +187,22 @@ impl SampleConfig {
         0.2
     }
 
+    fn default_min_mutation_radius() -> f64 {
+        0.0001
+    }
+
+    fn default_max_mutation_radius() -> f64 {
+        0.1
+    }
+
     fn default_outside_limit() -> usize {
         100
     }
 
+    fn default_iteration_stride() -> usize {
+        1
+    }
+
     fn default_julia_set_param() -> Complex {
         Complex::new(0.0, 0.0)
     }
@@ -101,39 +211,796 @@ impl SampleConfig {
         Complex::new(1.0, 0.0)
     }
 
+    /// The orbit lengths recorded to each layer
+    pub fn layer_ranges(&self) -> Vec<RangeInclusive<usize>> {
+        match &self.layers {
+            Some(layers) => layers.iter().map(|[min, max]| *min..=*max).collect(),
+            None => {
+                let mut min = 0;
+                self.cutoffs
+                    .iter()
+                    .map(|cutoff| {
+                        let range = min..=*cutoff;
+                        min = cutoff + 1;
+                        range
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Whether orbits can ever be recorded to each layer.
+    /// Without cumulative layers an orbit only goes to the first layer its length is in,
+    /// so a layer whose range is covered by earlier layers never receives any.
+    /// Orbits no longer than skip_iterations have no points to record either
+    pub fn reachable_layers(&self) -> Vec<bool> {
+        let ranges = self.layer_ranges();
+        (0..ranges.len())
+            .map(|layer| {
+                let earlier = match self.cumulative_layers {
+                    true => &ranges[..0],
+                    false => &ranges[..layer],
+                };
+                // The shortest length in the layer's range that no earlier layer takes
+                let mut len = (*ranges[layer].start()).max(self.skip_iterations + 1);
+                while let Some(range) = earlier.iter().find(|range| range.contains(&len)) {
+                    len = range.end() + 1;
+                }
+                len <= *ranges[layer].end()
+            })
+            .collect()
+    }
+
+    pub fn layer_count(&self) -> usize {
+        match &self.layers {
+            Some(layers) => layers.len(),
+            None => self.cutoffs.len(),
+        }
+    }
+
+    /// Orbits are iterated up to the longest length any layer records
+    pub fn max_iteration(&self) -> usize {
+        match &self.layers {
+            Some(layers) => layers.iter().map(|[_, max]| *max).max().unwrap_or(0),
+            None => self.cutoffs.iter().copied().max().unwrap_or(0),
+        }
+    }
+
+    /// Check the options that do not depend on how sampling is run
+    pub fn validate(&self) -> EscapeResult {
+        match &self.layers {
+            Some(_) if !self.cutoffs.is_empty() => {
+                let msg = "Only one of cutoffs and layers can be given".to_string();
+                return Err(EscapeError::InvalidSampleConfig(msg));
+            }
+            Some(layers) => {
+                if layers.is_empty() {
+                    let msg = "Layers must not be empty".to_string();
+                    return Err(EscapeError::InvalidSampleConfig(msg));
+                }
+                if let Some([min, max]) = layers.iter().find(|[min, max]| min > max || *max == 0) {
+                    let msg = format!(
+                        "Layer ranges must satisfy min <= max and max > 0, found [{}, {}]",
+                        min, max
+                    );
+                    return Err(EscapeError::InvalidSampleConfig(msg));
+                }
+            }
+            None => {
+                if self.cutoffs.is_empty() {
+                    let msg = "Either cutoffs or layers must be given".to_string();
+                    return Err(EscapeError::InvalidSampleConfig(msg));
+                }
+            }
+        }
+
+        if self.symmetry {
+            if self.julia_set_param.im != 0.0 {
+                let msg = format!(
+                    "Symmetry requires a real julia set param, found {}",
+                    self.julia_set_param
+                );
+                return Err(EscapeError::InvalidSampleConfig(msg));
+            }
+
+            if self.mandelbrot_param.im != 0.0 {
+                let msg = format!(
+                    "Symmetry requires a real mandelbrot param, found {}",
+                    self.mandelbrot_param
+                );
+                return Err(EscapeError::InvalidSampleConfig(msg));
+            }
+
+            // The conjugate orbit is recorded without being sampled, so it has to cross
+            // the view exactly as often as the orbit does
+            if self.view.center.im != 0.0 {
+                let msg = format!(
+                    "Symmetry requires a view centered on the real axis, found {}",
+                    self.view.center
+                );
+                return Err(EscapeError::InvalidSampleConfig(msg));
+            }
+        }
+
+        if self.iteration_stride == 0 {
+            let msg = "Iteration stride must be at least 1".to_string();
+            return Err(EscapeError::InvalidSampleConfig(msg));
+        }
+
+        if self.last_iterations == Some(0) {
+            let msg = "Last iterations must be at least 1".to_string();
+            return Err(EscapeError::InvalidSampleConfig(msg));
+        }
+
+        if self.min_mutation_radius <= 0.0 || self.max_mutation_radius <= self.min_mutation_radius {
+            let msg = format!(
+                "Mutation radii must satisfy 0 < min < max, found {} and {}",
+                self.min_mutation_radius, self.max_mutation_radius
+            );
+            return Err(EscapeError::InvalidSampleConfig(msg));
+        }
+
+        if let Some(convergence) = &self.convergence {
+            convergence.validate(self.layer_count())?;
+        }
+
+        if let Some(spectral) = &self.spectral {
+            spectral.validate()?;
+        }
+
+        if let Some(c_domain) = &self.c_domain {
+            c_domain.validate()?;
+            if self.symmetry && !c_domain.is_conjugate_symmetric() {
+                let msg = format!(
+                    "Symmetry requires a c domain that is symmetric about the real axis, found {:?}",
+                    c_domain
+                );
+                return Err(EscapeError::InvalidSampleConfig(msg));
+            }
+        }
+
+        if let Some(window) = &self.c_density {
+            if window.width == 0 || window.height == 0 || window.zoom <= 0.0 {
+                let msg = format!(
+                    "C density window must have a size and positive zoom, found {}x{} at zoom {}",
+                    window.width, window.height, window.zoom
+                );
+                return Err(EscapeError::InvalidSampleConfig(msg));
+            }
+        }
+
+        if self.mask.is_some() && self.symmetry {
+            let msg = "Symmetry can't be used with a mask, masks are not mirrored".to_string();
+            return Err(EscapeError::InvalidSampleConfig(msg));
+        }
+
+        if let Some(orbit_traps) = &self.orbit_traps {
+            orbit_traps.validate()?;
+            if self.symmetry {
+                let msg =
+                    "Symmetry can't be used with orbit traps, traps are not mirrored".to_string();
+                return Err(EscapeError::InvalidSampleConfig(msg));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Results can only be merged if they recorded the same orbits into the same grids
     pub fn compatible(&self, other: &Self) -> bool {
-        self.cutoffs.len() == other.cutoffs.len()
+        self.layer_ranges() == other.layer_ranges()
+            && self.cumulative_layers == other.cumulative_layers
+            && self.skip_iterations == other.skip_iterations
+            && self.last_iterations == other.last_iterations
+            && self.iteration_stride == other.iteration_stride
+            && self.weighting == other.weighting
+            && self.orbit_traps == other.orbit_traps
+            && self.mask == other.mask
             && self.view.width == other.view.width
             && self.view.height == other.view.height
     }
 }
 
+/// TemperingConfig describes ladders of chains run at different temperatures.
+/// Workers are grouped into ladders with one worker per temperature,
+/// and only the cold chain of each ladder records its orbits.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct TemperingConfig {
+    /// The temperature of each rung in a ladder.
+    /// The first must be 1.0, and they should be increasing.
+    /// A chain with temperature T samples from contribution^(1/T)
+    pub temperatures: Vec<f64>,
+
+    /// How many samples a chain takes between proposing swaps with its neighbors
+    /// Default value is 1000
+    #[serde(default = "TemperingConfig::default_swap_interval")]
+    pub swap_interval: usize,
+}
+
+impl TemperingConfig {
+    fn default_swap_interval() -> usize {
+        1000
+    }
+
+    pub fn ladder_size(&self) -> usize {
+        self.temperatures.len()
+    }
+
+    pub fn validate(&self, workers: usize) -> EscapeResult {
+        if self.temperatures.is_empty() {
+            let msg = "Tempering requires at least one temperature".to_string();
+            return Err(EscapeError::InvalidSampleConfig(msg));
+        }
+
+        if self.temperatures[0] != 1.0 {
+            let msg = format!(
+                "The first tempering temperature must be 1.0, found {}",
+                self.temperatures[0]
+            );
+            return Err(EscapeError::InvalidSampleConfig(msg));
+        }
+
+        for pair in self.temperatures.windows(2) {
+            if pair[1] <= pair[0] {
+                let msg = format!(
+                    "Tempering temperatures must be increasing, found {} then {}",
+                    pair[0], pair[1]
+                );
+                return Err(EscapeError::InvalidSampleConfig(msg));
+            }
+        }
+
+        if self.swap_interval == 0 {
+            let msg = "Tempering swap interval must be greater than 0".to_string();
+            return Err(EscapeError::InvalidSampleConfig(msg));
+        }
+
+        if !workers.is_multiple_of(self.ladder_size()) {
+            let msg = format!(
+                "{} workers can not be split into ladders of {} temperatures",
+                workers,
+                self.ladder_size()
+            );
+            return Err(EscapeError::InvalidSampleConfig(msg));
+        }
+
+        Ok(())
+    }
+}
+
+/// ConvergenceConfig describes when a histogram is sampled well enough to stop.
+/// The relative error of each layer is estimated from how much
+/// the independent histograms of the workers disagree.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ConvergenceConfig {
+    /// The relative error each cutoff layer has to reach.
+    /// Either one target for all layers, or one per cutoff
+    pub target_errors: Vec<f64>,
+
+    /// Seconds between estimates
+    /// Default value is 30
+    #[serde(default = "ConvergenceConfig::default_check_interval")]
+    pub check_interval: u64,
+}
+
+impl ConvergenceConfig {
+    fn default_check_interval() -> u64 {
+        30
+    }
+
+    pub fn target(&self, layer: usize) -> f64 {
+        if self.target_errors.len() == 1 {
+            self.target_errors[0]
+        } else {
+            self.target_errors[layer]
+        }
+    }
+
+    pub fn validate(&self, cutoffs: usize) -> EscapeResult {
+        if self.target_errors.len() != 1 && self.target_errors.len() != cutoffs {
+            let msg = format!(
+                "Expected 1 or {} convergence targets, found {}",
+                cutoffs,
+                self.target_errors.len()
+            );
+            return Err(EscapeError::InvalidSampleConfig(msg));
+        }
+
+        if self.target_errors.iter().any(|target| *target <= 0.0) {
+            let msg = "Convergence targets must be greater than 0".to_string();
+            return Err(EscapeError::InvalidSampleConfig(msg));
+        }
+
+        if self.check_interval == 0 {
+            let msg = "Convergence check interval must be greater than 0".to_string();
+            return Err(EscapeError::InvalidSampleConfig(msg));
+        }
+
+        Ok(())
+    }
+}
+
+/// Weighting describes what each recorded point adds to the weight channels.
+/// Dividing the weights by the counts gives a mean per pixel that does not depend on density
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Weighting {
+    /// The point's position in its orbit, from 0 at the start to 1 at escape
+    IterationIndex,
+
+    /// The norm of z at the point
+    Norm,
+
+    /// The distance from the point to the next one in its orbit
+    StepLength,
+
+    /// The length of the point's orbit, raised to an exponent
+    OrbitLength { exponent: f64 },
+}
+
+impl Weighting {
+    /// Weights are recorded in the channels weight_0, weight_1, ...
+    pub const CHANNEL: &'static str = "weight";
+}
+
+/// SpectralConfig describes how orbit lengths map to colors.
+/// Lengths are placed between min_length and max_length on a log scale,
+/// then mapped along the visible spectrum from violet to red, or along a palette.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SpectralConfig {
+    /// The orbit length mapped to the start of the spectrum
+    /// Default value is 1
+    #[serde(default = "SpectralConfig::default_min_length")]
+    pub min_length: usize,
+
+    /// The orbit length mapped to the end of the spectrum
+    pub max_length: usize,
+
+    /// Colors to interpolate between instead of the visible spectrum, as rgb in [0, 1]
+    /// Default value is empty, using the visible spectrum
+    #[serde(default)]
+    pub palette: Vec<[f64; 3]>,
+}
+
+impl SpectralConfig {
+    /// Spectral colors are recorded in these channels
+    pub const CHANNELS: [&'static str; 3] = ["spectral_r", "spectral_g", "spectral_b"];
+
+    fn default_min_length() -> usize {
+        1
+    }
+
+    pub fn validate(&self) -> EscapeResult {
+        if self.min_length == 0 || self.max_length <= self.min_length {
+            let msg = format!(
+                "Spectral lengths must satisfy 0 < min < max, found {} and {}",
+                self.min_length, self.max_length
+            );
+            return Err(EscapeError::InvalidSampleConfig(msg));
+        }
+        Ok(())
+    }
+
+    /// Where an orbit length falls between min_length and max_length, in [0, 1]
+    pub fn position(&self, orbit_len: usize) -> f64 {
+        let min = (self.min_length as f64).ln();
+        let max = (self.max_length as f64).ln();
+        let len = (orbit_len.max(1) as f64).ln();
+        ((len - min) / (max - min)).clamp(0.0, 1.0)
+    }
+
+    /// The color an orbit length maps to, as rgb in [0, 1]
+    pub fn color(&self, orbit_len: usize) -> [f64; 3] {
+        let t = self.position(orbit_len);
+        match self.palette.len() {
+            0 => wavelength_to_rgb(380.0 + t * 320.0),
+            1 => self.palette[0],
+            n => {
+                let scaled = t * (n - 1) as f64;
+                let i = (scaled.floor() as usize).min(n - 2);
+                let f = scaled - i as f64;
+                let (a, b) = (self.palette[i], self.palette[i + 1]);
+                [
+                    a[0] + f * (b[0] - a[0]),
+                    a[1] + f * (b[1] - a[1]),
+                    a[2] + f * (b[2] - a[2]),
+                ]
+            }
+        }
+    }
+}
+
+/// Approximate rgb in [0, 1] of light with a wavelength in nanometers, from 380 to 700
+/// This is Dan Bruton's piecewise linear approximation, dimmed towards the ends
+fn wavelength_to_rgb(wavelength: f64) -> [f64; 3] {
+    let w = wavelength;
+    let (r, g, b) = if w < 440.0 {
+        ((440.0 - w) / 60.0, 0.0, 1.0)
+    } else if w < 490.0 {
+        (0.0, (w - 440.0) / 50.0, 1.0)
+    } else if w < 510.0 {
+        (0.0, 1.0, (510.0 - w) / 20.0)
+    } else if w < 580.0 {
+        ((w - 510.0) / 70.0, 1.0, 0.0)
+    } else if w < 645.0 {
+        (1.0, (645.0 - w) / 65.0, 0.0)
+    } else {
+        (1.0, 0.0, 0.0)
+    };
+    let intensity = if w < 420.0 {
+        0.3 + 0.7 * (w - 380.0) / 40.0
+    } else if w > 680.0 {
+        0.3 + 0.7 * (700.0 - w) / 20.0
+    } else {
+        1.0
+    };
+    [r * intensity, g * intensity, b * intensity]
+}
+
+/// OrbitTrapConfig describes shapes in the complex plane that orbits have to pass through.
+/// An orbit is trapped once one of its points comes within the threshold of a trap
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct OrbitTrapConfig {
+    pub traps: Vec<OrbitTrap>,
+
+    /// How close a point has to come to a trap
+    /// Default value is 0.01
+    #[serde(default = "OrbitTrapConfig::default_threshold")]
+    pub threshold: f64,
+
+    /// Record only the points from the first one that hits a trap, instead of the whole orbit
+    /// Default value is false
+    #[serde(default)]
+    pub after_hit: bool,
+
+    /// Accumulate the distance from each recorded point to the nearest trap,
+    /// into a float channel per layer alongside the counts
+    /// Default value is false
+    #[serde(default)]
+    pub distance_channel: bool,
+}
+
+impl OrbitTrapConfig {
+    /// Trap distances are recorded in the channels trap_distance_0, trap_distance_1, ...
+    pub const CHANNEL: &'static str = "trap_distance";
+
+    fn default_threshold() -> f64 {
+        0.01
+    }
+
+    pub fn validate(&self) -> EscapeResult {
+        if self.traps.is_empty() {
+            let msg = "Orbit traps must not be empty".to_string();
+            return Err(EscapeError::InvalidSampleConfig(msg));
+        }
+        if self.threshold < 0.0 {
+            let msg = format!(
+                "Orbit trap threshold must not be negative, found {}",
+                self.threshold
+            );
+            return Err(EscapeError::InvalidSampleConfig(msg));
+        }
+        Ok(())
+    }
+
+    /// The distance from z to the nearest trap
+    pub fn distance(&self, z: &Complex) -> f64 {
+        self.traps
+            .iter()
+            .map(|trap| trap.distance(z))
+            .fold(f64::INFINITY, f64::min)
+    }
+
+    pub fn traps(&self, z: &Complex) -> bool {
+        self.distance(z) <= self.threshold
+    }
+}
+
+/// OrbitTrap is a shape in the complex plane
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OrbitTrap {
+    /// The outline of a circle
+    Circle { center: Complex, radius: f64 },
+
+    /// The line through a point at an angle to the real axis, in radians
+    Line { point: Complex, angle: f64 },
+
+    /// The horizontal and vertical lines through a point
+    Cross { center: Complex },
+
+    /// A filled rectangle between two corners
+    Box { min: Complex, max: Complex },
+}
+
+impl OrbitTrap {
+    /// The distance from z to the trap, 0 inside a box
+    pub fn distance(&self, z: &Complex) -> f64 {
+        match self {
+            OrbitTrap::Circle { center, radius } => ((z - center).norm_sqr().sqrt() - radius).abs(),
+            OrbitTrap::Line { point, angle } => {
+                let d = z - point;
+                (d.im * angle.cos() - d.re * angle.sin()).abs()
+            }
+            OrbitTrap::Cross { center } => {
+                let d = z - center;
+                d.re.abs().min(d.im.abs())
+            }
+            OrbitTrap::Box { min, max } => {
+                let dx = (min.re - z.re).max(z.re - max.re).max(0.0);
+                let dy = (min.im - z.im).max(z.im - max.im).max(0.0);
+                (dx * dx + dy * dy).sqrt()
+            }
+        }
+    }
+}
+
+/// CDomain is a region of the complex plane that samples are restricted to.
+/// Random restarts are drawn from it, and mutations that leave it are rejected
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CDomain {
+    /// The rectangle between two corners
+    Rectangle { min: Complex, max: Complex },
+
+    /// The ring between two circles around a center
+    Annulus {
+        center: Complex,
+        inner_radius: f64,
+        outer_radius: f64,
+    },
+
+    /// The inside of a polygon, by the even-odd rule
+    Polygon { points: Vec<Complex> },
+
+    /// The union of a list of disks
+    Disks { disks: Vec<Disk> },
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Disk {
+    pub center: Complex,
+    pub radius: f64,
+}
+
+impl CDomain {
+    pub fn validate(&self) -> EscapeResult {
+        let valid = match self {
+            CDomain::Rectangle { min, max } => min.re < max.re && min.im < max.im,
+            CDomain::Annulus {
+                inner_radius,
+                outer_radius,
+                ..
+            } => 0.0 <= *inner_radius && inner_radius < outer_radius,
+            CDomain::Polygon { points } => points.len() >= 3 && polygon_area(points) > 0.0,
+            CDomain::Disks { disks } => {
+                !disks.is_empty() && disks.iter().all(|disk| disk.radius > 0.0)
+            }
+        };
+        if !valid {
+            let msg = format!("C domain has no area: {:?}", self);
+            return Err(EscapeError::InvalidSampleConfig(msg));
+        }
+        Ok(())
+    }
+
+    /// Whether the conjugate of every point in the domain is in it too
+    /// Polygons must list the same points mirrored, in either direction
+    pub fn is_conjugate_symmetric(&self) -> bool {
+        match self {
+            CDomain::Rectangle { min, max } => min.im == -max.im,
+            CDomain::Annulus { center, .. } => center.im == 0.0,
+            CDomain::Polygon { points } => {
+                let mirrored: Vec<Complex> = points.iter().map(|point| point.conj()).collect();
+                let reversed: Vec<Complex> = mirrored.iter().rev().copied().collect();
+                (0..points.len()).any(|shift| {
+                    let rotated =
+                        |p: &[Complex]| p.iter().cycle().skip(shift).take(p.len()).eq(points);
+                    rotated(&mirrored) || rotated(&reversed)
+                })
+            }
+            CDomain::Disks { disks } => disks.iter().all(|disk| {
+                disks
+                    .iter()
+                    .any(|other| other.center == disk.center.conj() && other.radius == disk.radius)
+            }),
+        }
+    }
+
+    pub fn contains(&self, c: &Complex) -> bool {
+        match self {
+            CDomain::Rectangle { min, max } => {
+                min.re <= c.re && c.re <= max.re && min.im <= c.im && c.im <= max.im
+            }
+            CDomain::Annulus {
+                center,
+                inner_radius,
+                outer_radius,
+            } => {
+                let distance_sqr = (c - center).norm_sqr();
+                inner_radius * inner_radius <= distance_sqr
+                    && distance_sqr <= outer_radius * outer_radius
+            }
+            CDomain::Polygon { points } => {
+                let mut inside = false;
+                let mut j = points.len() - 1;
+                for i in 0..points.len() {
+                    let (a, b) = (points[i], points[j]);
+                    if (a.im > c.im) != (b.im > c.im)
+                        && c.re < (b.re - a.re) * (c.im - a.im) / (b.im - a.im) + a.re
+                    {
+                        inside = !inside;
+                    }
+                    j = i;
+                }
+                inside
+            }
+            CDomain::Disks { disks } => disks
+                .iter()
+                .any(|disk| (c - disk.center).norm_sqr() <= disk.radius * disk.radius),
+        }
+    }
+
+    /// The corners of a rectangle containing the domain
+    pub fn bounds(&self) -> (Complex, Complex) {
+        let disk_bounds = |center: Complex, radius: f64| {
+            let r = Complex::new(radius, radius);
+            (center - r, center + r)
+        };
+        let union = |bounds: &mut Option<(Complex, Complex)>, (min, max): (Complex, Complex)| {
+            *bounds = Some(match *bounds {
+                Some((a, b)) => (
+                    Complex::new(a.re.min(min.re), a.im.min(min.im)),
+                    Complex::new(b.re.max(max.re), b.im.max(max.im)),
+                ),
+                None => (min, max),
+            });
+        };
+
+        let mut bounds = None;
+        match self {
+            CDomain::Rectangle { min, max } => union(&mut bounds, (*min, *max)),
+            CDomain::Annulus {
+                center,
+                outer_radius,
+                ..
+            } => union(&mut bounds, disk_bounds(*center, *outer_radius)),
+            CDomain::Polygon { points } => {
+                for point in points {
+                    union(&mut bounds, (*point, *point));
+                }
+            }
+            CDomain::Disks { disks } => {
+                for disk in disks {
+                    union(&mut bounds, disk_bounds(disk.center, disk.radius));
+                }
+            }
+        }
+        bounds.unwrap_or_default()
+    }
+}
+
+/// The area of a polygon by the shoelace formula, whichever direction its points go in
+fn polygon_area(points: &[Complex]) -> f64 {
+    let mut twice_area = 0.0;
+    let mut j = points.len() - 1;
+    for i in 0..points.len() {
+        twice_area += points[j].re * points[i].im - points[i].re * points[j].im;
+        j = i;
+    }
+    twice_area.abs() / 2.0
+}
+
+/// MaskConfig points to a grayscale image that weights the view.
+/// Contributions and recorded points are weighted by the mask's value,
+/// so black areas stay empty and bright ones fill in
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct MaskConfig {
+    /// The image is scaled to the view if their sizes differ
+    pub path: PathBuf,
+
+    /// Hash of the image file, filled in when sampling so results record which mask they used.
+    /// Sampling fails if the file no longer matches
+    /// Default value is None
+    #[serde(default)]
+    pub hash: Option<String>,
+}
+
+/// The channels recorded with escape_channels, each with one grid per layer.
+/// The cosine and sine of the angle z escapes at, and a smooth escape time
+pub const ESCAPE_CHANNELS: [&str; 3] = ["escape_cos", "escape_sin", "escape_time"];
+
+/// The channel recorded with c_density, over its own window rather than the view
+pub const C_DENSITY_CHANNEL: &str = "c_density";
+
 /// DrawConfig is used to color histogram results
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct DrawConfig {
+    /// A color and power per layer, these may be left out for modes that don't use them
+    #[serde(default)]
     pub colors: Vec<[i32; 3]>,
+    #[serde(default)]
     pub powers: Vec<f64>,
     pub background_color: [f64; 3],
+
+    /// What the brightness of each layer's color comes from
+    /// Default value is density
+    #[serde(default)]
+    pub mode: DrawMode,
+}
+
+/// DrawMode selects the values that are colored
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DrawMode {
+    /// The counts of each layer, normalized by the layer's max count
+    #[default]
+    Density,
+
+    /// The mean of a per-layer channel over the points recorded to each pixel,
+    /// normalized by the layer's max mean
+    Mean { channel: String },
+
+    /// Hue from the mean escape angle over all layers, and value from the total density
+    /// Layer colors are not used
+    EscapeAngle {
+        #[serde(default = "DrawMode::default_power")]
+        power: f64,
+    },
+
+    /// The spectral channels, scaled so the brightest pixel is white or a pure color
+    /// The power is applied to brightness, keeping each pixel's hue
+    /// Layer colors are not used
+    Spectral {
+        #[serde(default = "DrawMode::default_power")]
+        power: f64,
+    },
+
+    /// The c_density channel, normalized by its max and drawn in white over the background
+    /// The image has the size of the c density window rather than the view
+    /// Layer colors are not used
+    CDensity {
+        #[serde(default = "DrawMode::default_power")]
+        power: f64,
+    },
+}
+
+impl DrawMode {
+    fn default_power() -> f64 {
+        1.0
+    }
+
+    /// Whether the colors and powers of the draw config are applied per layer
+    pub fn uses_layer_colors(&self) -> bool {
+        !matches!(
+            self,
+            DrawMode::EscapeAngle { .. } | DrawMode::Spectral { .. } | DrawMode::CDensity { .. }
+        )
+    }
 }
 
 impl DrawConfig {
     pub fn compatible(&self, sample_config: &SampleConfig) -> EscapeResult {
-        let cutoff_count = sample_config.cutoffs.len();
+        if !self.mode.uses_layer_colors() {
+            return Ok(());
+        }
+
+        let layer_count = sample_config.layer_count();
         let colors_count = self.colors.len();
         let powers_count = self.powers.len();
 
-        if colors_count != cutoff_count {
+        if colors_count != layer_count {
             let msg = format!(
-                "Sample config had {} cutoffs, draw config had {} colors",
-                cutoff_count, colors_count
+                "Sample config had {} layers, draw config had {} colors",
+                layer_count, colors_count
             );
             return Err(EscapeError::IncompatibleDrawConfig(msg));
         }
 
-        if powers_count != cutoff_count {
+        if powers_count != layer_count {
             let msg = format!(
-                "Sample config had {} cutoffs, draw config had {} powers",
-                cutoff_count, powers_count
+                "Sample config had {} layers, draw config had {} powers",
+                layer_count, powers_count
             );
             return Err(EscapeError::IncompatibleDrawConfig(msg));
         }
@@ -0,0 +1,173 @@
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::config::ConvergenceConfig;
+use crate::grid::HistogramGrid;
+use crate::sample::{ArcSwitch, Sampler};
+use crate::types::EscapeError;
+
+/// Estimate the relative error of the average of independent histograms of the same layer
+///
+/// Each histogram is normalized by its total, since workers don't sample at the same rate.
+/// The standard error of each cell's mean is taken from the spread between histograms,
+/// and the result is the norm of those errors relative to the norm of the mean image.
+/// Histograms that are still empty are left out, and the error is infinite if fewer than
+/// two remain. Returns None if every histogram is empty, as no orbit has reached the layer.
+pub fn relative_error(grids: &[&HistogramGrid]) -> Option<f64> {
+    let dense: Vec<_> = grids
+        .iter()
+        .map(|grid| grid.to_dense())
+        .filter(|grid| grid.data().iter().any(|count| *count > 0))
+        .collect();
+    if dense.is_empty() {
+        return None;
+    }
+    if dense.len() < 2 {
+        return Some(f64::INFINITY);
+    }
+    let totals: Vec<f64> = dense
+        .iter()
+        .map(|grid| grid.data().iter().sum::<u64>() as f64)
+        .collect();
+
+    let k = dense.len() as f64;
+    let mut error_sqr = 0.0;
+    let mut mean_sqr = 0.0;
+    let mut densities = vec![0.0; dense.len()];
+    for cell in 0..dense[0].data().len() {
+        for (i, grid) in dense.iter().enumerate() {
+            densities[i] = grid.data()[cell] as f64 / totals[i];
+        }
+        let mean = densities.iter().sum::<f64>() / k;
+        let variance = densities.iter().map(|d| (d - mean).powi(2)).sum::<f64>() / (k - 1.0);
+        error_sqr += variance / k;
+        mean_sqr += mean * mean;
+    }
+
+    Some((error_sqr / mean_sqr).sqrt())
+}
+
+/// Check that a run can estimate its convergence,
+/// which needs at least two workers recording to their own grids
+pub fn check_workers(recording_workers: usize, shared_grids: bool) -> Result<(), EscapeError> {
+    if shared_grids {
+        let msg = "Convergence needs the grids of each worker, so it can't use shared grids";
+        return Err(EscapeError::InvalidSampleConfig(msg.to_string()));
+    }
+
+    if recording_workers < 2 {
+        let msg = format!(
+            "Convergence needs at least 2 workers recording orbits, found {}",
+            recording_workers
+        );
+        return Err(EscapeError::InvalidSampleConfig(msg));
+    }
+
+    Ok(())
+}
+
+/// Estimate the error of each layer every check interval,
+/// and trigger the stop switch once every layer has reached its target
+pub async fn stop_on_convergence(
+    sampler: &Sampler,
+    convergence: &ConvergenceConfig,
+    stop_switch: &ArcSwitch,
+) {
+    let interval = Duration::from_secs(convergence.check_interval);
+    let reachable = sampler.config().reachable_layers();
+    let unreachable: Vec<usize> = (0..reachable.len())
+        .filter(|layer| !reachable[*layer])
+        .collect();
+    if !unreachable.is_empty() {
+        warn!(
+            "No orbits can reach layers {:?}, they are left out of convergence checks",
+            unreachable
+        );
+    }
+    loop {
+        let next = tokio::time::Instant::now() + interval;
+        while tokio::time::Instant::now() < next {
+            if stop_switch.read().stop() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+
+        let worker_grids = match sampler.worker_snapshots().await {
+            Some(worker_grids) => worker_grids,
+            None => return,
+        };
+
+        // Comparing every cell of every worker is slow for big views, so keep it off the runtime
+        let layers = worker_grids.first().map_or(0, |grids| grids.len());
+        let errors = tokio::task::spawn_blocking(move || {
+            (0..layers)
+                .map(|layer| {
+                    let grids: Vec<&HistogramGrid> =
+                        worker_grids.iter().map(|grids| &grids[layer]).collect();
+                    relative_error(&grids)
+                })
+                .collect::<Vec<Option<f64>>>()
+        })
+        .await;
+        let errors = match errors {
+            Ok(errors) => errors,
+            Err(e) => {
+                error!("Convergence check failed: {:?}", e);
+                continue;
+            }
+        };
+
+        // A layer no orbit has reached yet has no estimate, and isn't converged
+        let converged = errors.iter().enumerate().all(|(layer, error)| {
+            !reachable[layer] || error.is_some_and(|error| error <= convergence.target(layer))
+        });
+        info!(
+            "Relative error per layer {:?}, targets {:?}",
+            errors
+                .iter()
+                .map(|error| error.unwrap_or(f64::INFINITY))
+                .collect::<Vec<f64>>(),
+            convergence.target_errors
+        );
+
+        if converged {
+            stop_switch.write().trigger("Histogram has converged");
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::CountGrid;
+
+    #[test]
+    fn relative_errors() {
+        let mut a = CountGrid::zero(2, 1);
+        a.set_value(10, 0, 0);
+        a.set_value(10, 1, 0);
+        let mut b = CountGrid::zero(2, 1);
+        b.set_value(200, 0, 0);
+        b.set_value(200, 1, 0);
+        let (a, b) = (HistogramGrid::Dense(a), HistogramGrid::Dense(b));
+
+        // Only the shape matters, not how much each worker sampled
+        assert_eq!(relative_error(&[&a, &b]), Some(0.0));
+        assert_eq!(relative_error(&[&a]), Some(f64::INFINITY));
+
+        // Empty histograms are left out, and a layer nothing has reached has no error
+        let empty = HistogramGrid::Dense(CountGrid::zero(2, 1));
+        assert_eq!(relative_error(&[&a, &b, &empty]), Some(0.0));
+        assert_eq!(relative_error(&[&a, &empty]), Some(f64::INFINITY));
+        assert_eq!(relative_error(&[&empty, &empty]), None);
+
+        let mut c = CountGrid::zero(2, 1);
+        c.set_value(30, 0, 0);
+        c.set_value(10, 1, 0);
+        let c = HistogramGrid::Dense(c);
+        let error = relative_error(&[&a, &c]).unwrap();
+        assert!(error > 0.1 && error < 0.5, "error was {}", error);
+    }
+}
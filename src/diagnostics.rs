@@ -0,0 +1,253 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Samples per window when tracking how the acceptance rate changes over a chain
+pub const ACCEPTANCE_WINDOW: usize = 1000;
+
+/// Autocorrelation is estimated up to this lag
+pub const MAX_LAG: usize = 100;
+
+/// Why a metro instance ended
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum MetroOutcome {
+    /// Took every sample it was configured to
+    Completed,
+
+    /// The stop switch was triggered
+    Stopped,
+
+    /// The worker's sample budget ran out
+    BudgetSpent,
+
+    /// No initial sample could be found
+    NoInitialSample,
+
+    /// Too many mutations in a row missed the view during warm up
+    WarmUpOutsideStreak,
+
+    /// Too many mutations in a row missed the view while sampling
+    OutsideStreak,
+}
+
+impl MetroOutcome {
+    pub fn is_failure(&self) -> bool {
+        matches!(
+            self,
+            MetroOutcome::NoInitialSample
+                | MetroOutcome::WarmUpOutsideStreak
+                | MetroOutcome::OutsideStreak
+        )
+    }
+}
+
+/// The trace of a single chain after warm up, reused between metro instances
+#[derive(Debug, Default)]
+pub struct ChainTrace {
+    contributions: Vec<f64>,
+    window_accepted: Vec<u64>,
+}
+
+impl ChainTrace {
+    pub fn clear(&mut self) {
+        self.contributions.clear();
+        self.window_accepted.clear();
+    }
+
+    /// Record the contribution of the chain's state after a proposal
+    pub fn step(&mut self, contrib: f64, accepted: bool) {
+        if self.contributions.len().is_multiple_of(ACCEPTANCE_WINDOW) {
+            self.window_accepted.push(0);
+        }
+        self.contributions.push(contrib);
+        if accepted {
+            *self.window_accepted.last_mut().unwrap() += 1;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.contributions.len()
+    }
+
+    /// Autocorrelation of the contribution at lags 1 through MAX_LAG, as far as the chain allows
+    /// Returns None if the contribution never changed
+    pub fn autocorrelation(&self) -> Option<Vec<f64>> {
+        let x = &self.contributions;
+        let n = x.len();
+        if n < 2 {
+            return None;
+        }
+
+        let mean = x.iter().sum::<f64>() / n as f64;
+        let variance = x.iter().map(|v| (v - mean).powi(2)).sum::<f64>();
+        if variance == 0.0 {
+            return None;
+        }
+
+        let lags = MAX_LAG.min(n - 1);
+        Some(
+            (1..=lags)
+                .map(|lag| {
+                    let covariance: f64 = x
+                        .iter()
+                        .zip(&x[lag..])
+                        .map(|(a, b)| (a - mean) * (b - mean))
+                        .sum();
+                    covariance / variance
+                })
+                .collect(),
+        )
+    }
+
+    /// The number of independent samples the chain is worth
+    /// The integrated autocorrelation time is summed until the autocorrelation first drops below zero
+    pub fn effective_sample_size(&self) -> f64 {
+        let n = self.len() as f64;
+        match self.autocorrelation() {
+            Some(rho) => {
+                let tau = 1.0 + 2.0 * rho.iter().take_while(|r| **r > 0.0).sum::<f64>();
+                n / tau
+            }
+            None => n.min(1.0),
+        }
+    }
+}
+
+/// Diagnostics accumulated over many chains
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    chains: u64,
+    outcomes: BTreeMap<MetroOutcome, u64>,
+    total_length: u64,
+    min_length: Option<u64>,
+    max_length: u64,
+    window_accepted: Vec<u64>,
+    window_samples: Vec<u64>,
+    autocorrelation_sums: Vec<f64>,
+    autocorrelation_counts: Vec<u64>,
+    effective_samples: f64,
+}
+
+/// Summary of the chains of a run, written next to the histogram
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DiagnosticsReport {
+    pub chains: u64,
+    pub outcomes: BTreeMap<MetroOutcome, u64>,
+    pub mean_chain_length: f64,
+    pub min_chain_length: u64,
+    pub max_chain_length: u64,
+    pub acceptance_rate: f64,
+
+    /// Acceptance rate for each window of samples since warm up, averaged over chains
+    pub acceptance_window: usize,
+    pub acceptance_over_time: Vec<f64>,
+
+    /// Mean autocorrelation of the contribution, starting at lag 1
+    pub autocorrelation: Vec<f64>,
+    pub effective_sample_size: f64,
+    pub mean_effective_sample_size: f64,
+
+    /// Effective samples per sample taken
+    pub sampling_efficiency: f64,
+}
+
+impl Diagnostics {
+    pub fn record_chain(&mut self, trace: &ChainTrace, outcome: MetroOutcome) {
+        self.chains += 1;
+        *self.outcomes.entry(outcome).or_insert(0) += 1;
+
+        let length = trace.len() as u64;
+        self.total_length += length;
+        self.min_length = Some(self.min_length.map_or(length, |min| min.min(length)));
+        self.max_length = self.max_length.max(length);
+
+        for (window, accepted) in trace.window_accepted.iter().enumerate() {
+            if self.window_accepted.len() <= window {
+                self.window_accepted.push(0);
+                self.window_samples.push(0);
+            }
+            let samples = (trace.len() - window * ACCEPTANCE_WINDOW).min(ACCEPTANCE_WINDOW);
+            self.window_accepted[window] += accepted;
+            self.window_samples[window] += samples as u64;
+        }
+
+        if let Some(rho) = trace.autocorrelation() {
+            if self.autocorrelation_sums.len() < rho.len() {
+                self.autocorrelation_sums.resize(rho.len(), 0.0);
+                self.autocorrelation_counts.resize(rho.len(), 0);
+            }
+            for (lag, r) in rho.iter().enumerate() {
+                self.autocorrelation_sums[lag] += r;
+                self.autocorrelation_counts[lag] += 1;
+            }
+        }
+        self.effective_samples += trace.effective_sample_size();
+    }
+
+    pub fn report(&self) -> DiagnosticsReport {
+        let ratio = |a: f64, b: f64| if b == 0.0 { 0.0 } else { a / b };
+        let accepted: u64 = self.window_accepted.iter().sum();
+
+        DiagnosticsReport {
+            chains: self.chains,
+            outcomes: self.outcomes.clone(),
+            mean_chain_length: ratio(self.total_length as f64, self.chains as f64),
+            min_chain_length: self.min_length.unwrap_or(0),
+            max_chain_length: self.max_length,
+            acceptance_rate: ratio(accepted as f64, self.total_length as f64),
+            acceptance_window: ACCEPTANCE_WINDOW,
+            acceptance_over_time: self
+                .window_accepted
+                .iter()
+                .zip(&self.window_samples)
+                .map(|(a, s)| ratio(*a as f64, *s as f64))
+                .collect(),
+            autocorrelation: self
+                .autocorrelation_sums
+                .iter()
+                .zip(&self.autocorrelation_counts)
+                .map(|(sum, count)| ratio(*sum, *count as f64))
+                .collect(),
+            effective_sample_size: self.effective_samples,
+            mean_effective_sample_size: ratio(self.effective_samples, self.chains as f64),
+            sampling_efficiency: ratio(self.effective_samples, self.total_length as f64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn effective_sample_sizes() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut independent = ChainTrace::default();
+        let mut sticky = ChainTrace::default();
+        let mut value = 0.5;
+        for _ in 0..10000 {
+            independent.step(rng.gen(), true);
+            if rng.gen::<f64>() < 0.05 {
+                value = rng.gen();
+                sticky.step(value, true);
+            } else {
+                sticky.step(value, false);
+            }
+        }
+
+        assert!(independent.effective_sample_size() > 8000.0);
+        assert!(sticky.effective_sample_size() < 1000.0);
+
+        let mut diagnostics = Diagnostics::default();
+        diagnostics.record_chain(&independent, MetroOutcome::Completed);
+        diagnostics.record_chain(&sticky, MetroOutcome::OutsideStreak);
+        let report = diagnostics.report();
+        assert_eq!(report.chains, 2);
+        assert_eq!(report.outcomes[&MetroOutcome::OutsideStreak], 1);
+        assert_eq!(report.acceptance_over_time.len(), 10);
+        assert!(report.acceptance_rate > 0.5 && report.acceptance_rate < 0.6);
+        assert_eq!(report.autocorrelation.len(), MAX_LAG);
+    }
+}
@@ -0,0 +1,684 @@
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::io::BufReader as StdBufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::{error, info, trace, warn};
+
+use crate::channels::ChannelGrids;
+use crate::cli_options::{CoordinatorOptions, WorkerOptions};
+use crate::config::SampleConfig;
+use crate::grid::HistogramGrid;
+use crate::histogram_result::HistogramResult;
+use crate::mask;
+use crate::memory::GridStrategy;
+use crate::sample::{ArcSwitch, Sampler, StopSwitch, WorkBudget};
+use crate::types::{CountGrid, EscapeError, EscapeResult};
+
+/// How long the coordinator waits for a worker's last results after asking it to stop
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Assignments only carry a config, so workers accept messages up to this long
+const MAX_ASSIGNMENT_LEN: usize = 1 << 24;
+
+/// How long the coordinator waits before accepting again after a failed accept
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Messages sent from the coordinator to a worker
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+enum CoordinatorMessage {
+    /// What to sample, the worker's threads are seeded with seed, seed + 1, ...
+    Assignment {
+        config: Box<SampleConfig>,
+        seed: u64,
+        delta_interval: u64,
+    },
+
+    /// Send any remaining results and disconnect
+    Stop,
+}
+
+/// Messages sent from a worker to the coordinator
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+enum WorkerMessage {
+    Hello {
+        workers: usize,
+    },
+
+    /// Counts recorded since the last delta
+    Delta {
+        grids: Vec<HistogramGrid>,
+    },
+
+    Goodbye,
+}
+
+/// Messages are sent as one line of json each
+async fn send_message<W: AsyncWrite + Unpin, M: Serialize>(
+    writer: &mut W,
+    message: &M,
+) -> EscapeResult {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Reads messages of at most max_len bytes, so a peer can't exhaust our memory
+/// Partial lines are kept between calls, so reading can be cancelled and resumed
+struct MessageReader {
+    reader: BufReader<OwnedReadHalf>,
+    line: Vec<u8>,
+    max_len: usize,
+}
+
+impl MessageReader {
+    fn new(read_half: OwnedReadHalf, max_len: usize) -> MessageReader {
+        MessageReader {
+            reader: BufReader::new(read_half),
+            line: Vec::new(),
+            max_len,
+        }
+    }
+
+    async fn read<M: serde::de::DeserializeOwned>(&mut self) -> Result<Option<M>, EscapeError> {
+        let remaining = (self.max_len + 1).saturating_sub(self.line.len()) as u64;
+        let read = (&mut self.reader)
+            .take(remaining)
+            .read_until(b'\n', &mut self.line)
+            .await?;
+        if self.line.last() == Some(&b'\n') {
+            let line = std::mem::take(&mut self.line);
+            return Ok(Some(serde_json::from_slice(&line)?));
+        }
+        if self.line.len() > self.max_len {
+            let msg = format!("Message is longer than {} bytes", self.max_len);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, msg).into());
+        }
+        if read == 0 && self.line.is_empty() {
+            return Ok(None);
+        }
+        Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into())
+    }
+}
+
+/// Deltas are the longest messages workers send,
+/// allow for every count having 20 digits, and for tiles overhanging the view
+fn max_delta_len(config: &SampleConfig) -> usize {
+    let tile_size = 64;
+    let width = config.view.width.div_ceil(tile_size) * tile_size;
+    let height = config.view.height.div_ceil(tile_size) * tile_size;
+    (1 << 20) + config.layer_count() * width * height * 21
+}
+
+/// State shared by the coordinator's connections
+struct Coordinator {
+    config: Arc<SampleConfig>,
+    grids: Mutex<Vec<HistogramGrid>>,
+    next_seed: Mutex<u64>,
+    delta_interval: u64,
+    stop_switch: ArcSwitch,
+}
+
+impl Coordinator {
+    /// Reserve a range of seeds for a worker's threads
+    fn reserve_seeds(&self, workers: usize) -> u64 {
+        let mut next_seed = self.next_seed.lock();
+        let result = *next_seed;
+        *next_seed = next_seed.wrapping_add(workers as u64);
+        result
+    }
+
+    fn accumulate(&self, delta: &[HistogramGrid]) -> EscapeResult {
+        let view = self.config.view;
+        if delta.len() != self.config.layer_count()
+            || delta
+                .iter()
+                .any(|grid| grid.dimensions() != (view.width, view.height))
+        {
+            return Err(EscapeError::IncompatibleHistograms);
+        }
+
+        let mut grids = self.grids.lock();
+        for (grid, delta_grid) in grids.iter_mut().zip(delta.iter()) {
+            grid.add(delta_grid);
+        }
+        Ok(())
+    }
+
+    /// Write the accumulated histogram, via a temporary file so that
+    /// an interrupted checkpoint doesn't clobber the last one
+    /// The grids are copied so that workers can keep sending deltas while it is written
+    async fn checkpoint(&self, path: &Path) -> EscapeResult {
+        let grids = self.grids.lock().clone();
+        let config = self.config.clone();
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || -> EscapeResult {
+            let mut temp_path = path.as_os_str().to_owned();
+            temp_path.push(".tmp");
+            let temp_path = PathBuf::from(temp_path);
+            HistogramResult::save(&config, &grids, &temp_path)?;
+            std::fs::rename(&temp_path, &path)?;
+            Ok(())
+        })
+        .await?
+    }
+}
+
+async fn handle_worker(
+    coordinator: Arc<Coordinator>,
+    stream: TcpStream,
+    peer: SocketAddr,
+) -> EscapeResult {
+    let (read_half, mut write_half) = stream.into_split();
+    let mut reader = MessageReader::new(read_half, max_delta_len(&coordinator.config));
+
+    let workers = match reader.read().await? {
+        Some(WorkerMessage::Hello { workers }) => workers,
+        other => {
+            warn!(%peer, "Expected hello from worker, got {:?}", other);
+            return Ok(());
+        }
+    };
+
+    // Each of the worker's ladders needs a thread per temperature
+    if let Some(tempering) = &coordinator.config.tempering {
+        if let Err(e) = tempering.validate(workers) {
+            warn!(%peer, workers, "Rejecting worker: {:?}", e);
+            return Ok(());
+        }
+    }
+
+    let seed = coordinator.reserve_seeds(workers);
+    let assignment = CoordinatorMessage::Assignment {
+        config: Box::new((*coordinator.config).clone()),
+        seed,
+        delta_interval: coordinator.delta_interval,
+    };
+    send_message(&mut write_half, &assignment).await?;
+    info!(%peer, workers, seed, "Worker joined");
+
+    let mut deltas = 0;
+    let mut stop_deadline = None;
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            message = reader.read() => match message {
+                Ok(Some(WorkerMessage::Delta { grids })) => {
+                    if let Err(e) = coordinator.accumulate(&grids) {
+                        error!(%peer, "Dropping worker with incompatible results: {:?}", e);
+                        break;
+                    }
+                    deltas += 1;
+                    trace!(%peer, deltas, "Accumulated worker delta");
+                }
+                Ok(Some(WorkerMessage::Goodbye)) => {
+                    info!(%peer, deltas, "Worker left");
+                    break;
+                }
+                Ok(Some(WorkerMessage::Hello { .. })) => {
+                    warn!(%peer, "Unexpected hello from worker");
+                }
+                Ok(None) => {
+                    warn!(%peer, deltas, "Worker disconnected");
+                    break;
+                }
+                Err(e) => {
+                    warn!(%peer, deltas, "Lost worker: {:?}", e);
+                    break;
+                }
+            },
+            _ = ticker.tick() => {
+                match stop_deadline {
+                    None => {
+                        if coordinator.stop_switch.read().stop() {
+                            send_message(&mut write_half, &CoordinatorMessage::Stop).await?;
+                            stop_deadline = Some(Instant::now() + STOP_TIMEOUT);
+                        }
+                    }
+                    Some(deadline) => {
+                        if Instant::now() > deadline {
+                            warn!(%peer, "Worker did not stop in time");
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn log_connection_end(peer: SocketAddr, result: Result<EscapeResult, tokio::task::JoinError>) {
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => warn!(%peer, "Worker connection ended with error: {:?}", e),
+        Err(e) => warn!(%peer, "Worker connection task failed: {:?}", e),
+    }
+}
+
+/// Accept workers and accumulate their results until the stop switch is triggered.
+/// Workers may join and leave at any time, whatever they sent before leaving is kept.
+async fn coordinate(
+    listener: TcpListener,
+    config: Arc<SampleConfig>,
+    seed: u64,
+    delta_interval: u64,
+    checkpoint: Option<(&Path, u64)>,
+    stop_switch: ArcSwitch,
+) -> Result<Vec<HistogramGrid>, EscapeError> {
+    config.validate()?;
+    if config.convergence.is_some() {
+        let msg =
+            "Convergence can't be estimated from the workers' combined results, use --duration";
+        return Err(EscapeError::InvalidSampleConfig(msg.to_string()));
+    }
+    if ChannelGrids::cell_count(&config) > 0 {
+        let msg = "Workers only send counts, weighting, escape_channels, spectral, \
+                   orbit_traps.distance_channel and c_density aren't supported by the coordinator";
+        return Err(EscapeError::InvalidSampleConfig(msg.to_string()));
+    }
+    let view = config.view;
+    let coordinator = Arc::new(Coordinator {
+        config: config.clone(),
+        grids: Mutex::new(vec![
+            HistogramGrid::Dense(CountGrid::zero(
+                view.width,
+                view.height
+            ));
+            config.layer_count()
+        ]),
+        next_seed: Mutex::new(seed),
+        delta_interval,
+        stop_switch: stop_switch.clone(),
+    });
+    info!("Listening for workers on {}", listener.local_addr()?);
+
+    // Connections report how they ended, so failures are logged without ending the run
+    let (ended_sender, mut ended) = mpsc::unbounded_channel();
+    let mut connections = 0;
+    let mut last_checkpoint = Instant::now();
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    trace!(%peer, "Accepted connection");
+                    let connection = tokio::spawn(handle_worker(coordinator.clone(), stream, peer));
+                    let ended_sender = ended_sender.clone();
+                    tokio::spawn(async move {
+                        let _ = ended_sender.send((peer, connection.await));
+                    });
+                    connections += 1;
+                }
+                Err(e) => {
+                    warn!("Failed to accept a worker: {:?}", e);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                }
+            },
+            Some((peer, result)) = ended.recv() => {
+                connections -= 1;
+                log_connection_end(peer, result);
+            }
+            _ = ticker.tick() => {
+                if stop_switch.read().stop() {
+                    break;
+                }
+
+                if let Some((path, interval)) = checkpoint {
+                    if last_checkpoint.elapsed() >= Duration::from_secs(interval) {
+                        match coordinator.checkpoint(path).await {
+                            Ok(()) => info!("Checkpoint written to {}", path.display()),
+                            Err(e) => warn!("Failed to write checkpoint: {:?}", e),
+                        }
+                        last_checkpoint = Instant::now();
+                    }
+                }
+            }
+        }
+    }
+
+    info!("Waiting for workers to send their last results");
+    while connections > 0 {
+        if let Some((peer, result)) = ended.recv().await {
+            connections -= 1;
+            log_connection_end(peer, result);
+        }
+    }
+
+    let grids = coordinator.grids.lock().clone();
+    Ok(grids)
+}
+
+/// Sample on behalf of a coordinator until it asks us to stop, or we are stopped locally
+async fn work_for_coordinator(
+    address: &str,
+    workers: usize,
+    stop_switch: ArcSwitch,
+) -> EscapeResult {
+    let stream = TcpStream::connect(address).await?;
+    let (read_half, mut write_half) = stream.into_split();
+    let mut reader = MessageReader::new(read_half, MAX_ASSIGNMENT_LEN);
+
+    send_message(&mut write_half, &WorkerMessage::Hello { workers }).await?;
+    let (config, seed, delta_interval) = match reader.read().await? {
+        Some(CoordinatorMessage::Assignment {
+            config,
+            seed,
+            delta_interval,
+        }) => (Arc::new(*config), seed, delta_interval),
+        other => {
+            warn!(
+                "Expected an assignment from the coordinator, got {:?}",
+                other
+            );
+            return Ok(());
+        }
+    };
+    info!(seed, "Received assignment from {}", address);
+
+    let sampler = Sampler::start(
+        config,
+        workers,
+        GridStrategy::Shared,
+        Some(seed),
+        WorkBudget::default(),
+        false,
+        stop_switch.clone(),
+    )?;
+
+    let mut connected = true;
+    let mut delta_ticker = tokio::time::interval(Duration::from_secs(delta_interval.max(1)));
+    delta_ticker.tick().await;
+    let mut stop_ticker = tokio::time::interval(Duration::from_secs(1));
+    while !stop_switch.read().stop() {
+        tokio::select! {
+            _ = delta_ticker.tick() => {
+                let grids = sampler.drain().unwrap();
+                if let Err(e) = send_message(&mut write_half, &WorkerMessage::Delta { grids }).await {
+                    warn!("Failed to send delta: {:?}", e);
+                    connected = false;
+                    stop_switch.write().trigger("Lost connection to coordinator");
+                }
+            }
+            message = reader.read::<CoordinatorMessage>() => match message {
+                Ok(Some(CoordinatorMessage::Stop)) => {
+                    stop_switch.write().trigger("Coordinator requested stop");
+                }
+                Ok(Some(other)) => warn!("Unexpected message from coordinator {:?}", other),
+                Ok(None) | Err(_) => {
+                    connected = false;
+                    stop_switch.write().trigger("Lost connection to coordinator");
+                }
+            },
+            _ = stop_ticker.tick() => {}
+        }
+    }
+
+    let grids = sampler.finish().await?;
+    if connected {
+        send_message(&mut write_half, &WorkerMessage::Delta { grids }).await?;
+        send_message(&mut write_half, &WorkerMessage::Goodbye).await?;
+        info!("Sent last results to coordinator");
+    } else {
+        warn!("Results since the last delta were lost");
+    }
+
+    Ok(())
+}
+
+async fn async_coordinator(cli_options: &CoordinatorOptions) -> EscapeResult {
+    let logger_builder = tracing_subscriber::fmt()
+        .with_timer(tracing_subscriber::fmt::time::uptime())
+        .with_thread_ids(true)
+        .with_max_level(&cli_options.verbosity);
+
+    if cli_options.pretty_logging {
+        logger_builder.pretty().init()
+    } else {
+        logger_builder.init();
+    }
+
+    let mut config_reader = StdBufReader::new(std::fs::File::open(&cli_options.config)?);
+    let mut config: SampleConfig = serde_json::from_reader(&mut config_reader)?;
+    // Workers check their copy of the mask against the coordinator's
+    mask::pin_hash(&mut config)?;
+    let config = Arc::new(config);
+    trace!("Sample config loaded: {}", &cli_options.config.display());
+
+    let seed = cli_options.seed.unwrap_or_else(rand::random);
+    let listener = TcpListener::bind(&cli_options.listen).await?;
+    let stop_switch = StopSwitch::new(&cli_options.duration).await;
+    let grids = coordinate(
+        listener,
+        config.clone(),
+        seed,
+        cli_options.delta_interval,
+        Some((&cli_options.output, cli_options.checkpoint_interval)),
+        stop_switch,
+    )
+    .await?;
+
+    HistogramResult::save(&config, &grids, &cli_options.output)?;
+    info!(
+        "Result has been written to {}",
+        &cli_options.output.display()
+    );
+
+    Ok(())
+}
+
+pub fn run_coordinator(coordinator_options: &CoordinatorOptions) -> EscapeResult {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async_coordinator(coordinator_options))?;
+
+    Ok(())
+}
+
+async fn async_worker(cli_options: &WorkerOptions) -> EscapeResult {
+    let logger_builder = tracing_subscriber::fmt()
+        .with_timer(tracing_subscriber::fmt::time::uptime())
+        .with_thread_ids(true)
+        .with_max_level(&cli_options.verbosity);
+
+    if cli_options.pretty_logging {
+        logger_builder.pretty().init()
+    } else {
+        logger_builder.init();
+    }
+
+    let stop_switch = StopSwitch::new(&None).await;
+    work_for_coordinator(&cli_options.connect, cli_options.workers, stop_switch).await
+}
+
+pub fn run_worker(worker_options: &WorkerOptions) -> EscapeResult {
+    // Note that we add one extra thread for the connection / signal handlers
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(worker_options.workers + 1)
+        .enable_all()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async_worker(worker_options))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn workers_join_and_leave() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let config: SampleConfig = serde_json::from_str(
+                r#"{
+                    "cutoffs": [20, 100],
+                    "view": { "width": 50, "height": 50, "zoom": 0.25, "center": [0.0, 0.0] },
+                    "samples": 500,
+                    "warm_up_samples": 10
+                }"#,
+            )
+            .unwrap();
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap().to_string();
+            let coordinator_switch = StopSwitch::without_handlers();
+            let coordinator = tokio::spawn(coordinate(
+                listener,
+                Arc::new(config),
+                7,
+                1,
+                None,
+                coordinator_switch.clone(),
+            ));
+
+            let staying_switch = StopSwitch::without_handlers();
+            let staying = {
+                let address = address.clone();
+                let switch = staying_switch.clone();
+                tokio::spawn(async move { work_for_coordinator(&address, 1, switch).await })
+            };
+
+            let leaving_switch = StopSwitch::without_handlers();
+            let leaving = {
+                let switch = leaving_switch.clone();
+                tokio::spawn(async move { work_for_coordinator(&address, 2, switch).await })
+            };
+
+            tokio::time::sleep(Duration::from_millis(1500)).await;
+            leaving_switch.write().trigger("Leaving");
+            leaving.await.unwrap().unwrap();
+
+            tokio::time::sleep(Duration::from_millis(1500)).await;
+            coordinator_switch.write().trigger("Stopping");
+            let grids = coordinator.await.unwrap().unwrap();
+            staying.await.unwrap().unwrap();
+
+            assert!(staying_switch.read().stop());
+            let total: u64 = grids
+                .iter()
+                .map(|grid| grid.to_dense().data().iter().sum::<u64>())
+                .sum();
+            assert!(total > 0);
+        });
+    }
+
+    #[test]
+    fn workers_must_fit_ladders() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let config: SampleConfig = serde_json::from_str(
+                r#"{
+                    "cutoffs": [20],
+                    "view": { "width": 50, "height": 50, "zoom": 0.25, "center": [0.0, 0.0] },
+                    "samples": 500,
+                    "warm_up_samples": 10,
+                    "tempering": { "temperatures": [1.0, 2.0] }
+                }"#,
+            )
+            .unwrap();
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap().to_string();
+            let coordinator_switch = StopSwitch::without_handlers();
+            let coordinator = tokio::spawn(coordinate(
+                listener,
+                Arc::new(config),
+                7,
+                1,
+                None,
+                coordinator_switch.clone(),
+            ));
+
+            // A worker with one thread can't run a ladder of two, and gets no assignment
+            work_for_coordinator(&address, 1, StopSwitch::without_handlers())
+                .await
+                .unwrap();
+
+            coordinator_switch.write().trigger("Stopping");
+            let grids = coordinator.await.unwrap().unwrap();
+            assert_eq!(grids[0].to_dense().data().iter().sum::<u64>(), 0);
+        });
+    }
+
+    #[test]
+    fn long_messages_are_rejected() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let mut client = TcpStream::connect(address).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            let (read_half, _write_half) = server.into_split();
+            let mut reader = MessageReader::new(read_half, 32);
+
+            client.write_all(b"{\"type\":\"Goodbye\"}\n").await.unwrap();
+            let message: Option<WorkerMessage> = reader.read().await.unwrap();
+            assert!(matches!(message, Some(WorkerMessage::Goodbye)));
+
+            client.write_all(&[b' '; 64]).await.unwrap();
+            assert!(reader.read::<WorkerMessage>().await.is_err());
+        });
+    }
+
+    #[test]
+    fn channels_are_rejected() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let config: SampleConfig = serde_json::from_str(
+                r#"{
+                    "cutoffs": [20],
+                    "view": { "width": 50, "height": 50, "zoom": 0.25, "center": [0.0, 0.0] },
+                    "escape_channels": true
+                }"#,
+            )
+            .unwrap();
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let result = coordinate(
+                listener,
+                Arc::new(config),
+                7,
+                1,
+                None,
+                StopSwitch::without_handlers(),
+            )
+            .await;
+            assert!(matches!(result, Err(EscapeError::InvalidSampleConfig(_))));
+        });
+    }
+}
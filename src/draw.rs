@@ -1,7 +1,11 @@
+use crate::channels::{self, layer_channel, Channels};
 use crate::cli_options::DrawOptions;
-use crate::config::{DrawConfig, SampleConfig};
+use crate::config::{
+    DrawConfig, DrawMode, SampleConfig, SpectralConfig, C_DENSITY_CHANNEL, ESCAPE_CHANNELS,
+};
+use crate::grid::HistogramGrid;
 use crate::histogram_result::HistogramResult;
-use crate::types::{EscapeResult, NormalizedGrid};
+use crate::types::{CountGrid, EscapeError, EscapeResult, NormalizedGrid};
 
 use std::io::BufReader;
 use tracing::info;
@@ -22,20 +26,18 @@ pub fn run_draw(draw_options: &DrawOptions) -> EscapeResult {
     let draw_config: DrawConfig = serde_json::from_reader(&mut config_reader)?;
     info!("Loaded draw config {}", &draw_options.config.display());
 
-    let (sample_config, count_grids) = HistogramResult::from_file(&draw_options.histogram)?;
-    draw_config.compatible(&sample_config)?;
+    let histogram = HistogramResult::load(&draw_options.histogram)?;
     info!(
         "Loaded histogram result {}",
         &draw_options.histogram.display()
     );
 
-    let normalized_grids: Vec<NormalizedGrid> = count_grids
-        .iter()
-        .map(|grid| grid.to_normalized_grid())
-        .collect();
-    info!("Grids have been normalized");
-
-    let image = color_grids(&draw_config, &sample_config, &normalized_grids);
+    let image = render(
+        &draw_config,
+        &histogram.config,
+        &histogram.grids,
+        &histogram.channels,
+    )?;
     info!("Image generated");
 
     image.save(&draw_options.output)?;
@@ -44,7 +46,209 @@ pub fn run_draw(draw_options: &DrawOptions) -> EscapeResult {
     Ok(())
 }
 
-fn color_grids(
+/// Color a histogram's grids according to the draw config
+pub fn render(
+    draw_config: &DrawConfig,
+    sample_config: &SampleConfig,
+    count_grids: &[HistogramGrid],
+    channels: &Channels,
+) -> Result<image::RgbImage, EscapeError> {
+    draw_config.compatible(sample_config)?;
+
+    let normalized_grids: Vec<NormalizedGrid> = match &draw_config.mode {
+        DrawMode::Density => count_grids
+            .iter()
+            .map(|grid| grid.to_dense().to_normalized_grid())
+            .collect(),
+        DrawMode::Mean { channel } => {
+            let mut result = Vec::with_capacity(count_grids.len());
+            for (layer, grid) in count_grids.iter().enumerate() {
+                let sums = channels::channel(channels, &layer_channel(channel, layer))?;
+                result.push(normalized_mean(sums, &grid.to_dense()));
+            }
+            result
+        }
+        DrawMode::EscapeAngle { power } => {
+            return color_escape_angles(draw_config, count_grids, channels, *power);
+        }
+        DrawMode::Spectral { power } => {
+            return color_spectral(draw_config, channels, *power);
+        }
+        DrawMode::CDensity { power } => {
+            return color_c_density(draw_config, channels, *power);
+        }
+    };
+    info!("Grids have been normalized");
+
+    Ok(color_grids(draw_config, sample_config, &normalized_grids))
+}
+
+/// Color each pixel with the hue of its mean escape angle, summed over the layers,
+/// and a value from its total density
+fn color_escape_angles(
+    draw_config: &DrawConfig,
+    count_grids: &[HistogramGrid],
+    channels: &Channels,
+    power: f64,
+) -> Result<image::RgbImage, EscapeError> {
+    let mut counts = count_grids[0].to_dense();
+    let (width, height) = counts.dimensions();
+    let mut cos = NormalizedGrid::zero(width, height);
+    let mut sin = NormalizedGrid::zero(width, height);
+    for (layer, grid) in count_grids.iter().enumerate() {
+        if layer > 0 {
+            counts.add(&grid.to_dense());
+        }
+        cos.add(channels::channel(
+            channels,
+            &layer_channel(ESCAPE_CHANNELS[0], layer),
+        )?);
+        sin.add(channels::channel(
+            channels,
+            &layer_channel(ESCAPE_CHANNELS[1], layer),
+        )?);
+    }
+    let density = counts.to_normalized_grid();
+    info!("Grids have been normalized");
+
+    let mut result = image::RgbImage::new(width as u32, height as u32);
+    for x in 0..width {
+        for y in 0..height {
+            // The hue goes once around the color wheel as the angle goes around the circle
+            let angle = sin.value(x, y).atan2(cos.value(x, y));
+            let hue = angle / std::f64::consts::TAU + 0.5;
+            let value = density.value(x, y).powf(power);
+            let color = hsv_to_rgb(hue, 1.0, value);
+
+            let mut rgb = [0, 0, 0];
+            for i in 0..3 {
+                rgb[i] =
+                    ((draw_config.background_color[i] + color[i]).clamp(0.0, 1.0) * 255.0) as u8;
+            }
+            result.put_pixel(x as u32, y as u32, image::Rgb(rgb));
+        }
+    }
+    Ok(result)
+}
+
+/// Tone map the spectral channels
+/// Each pixel's brightest channel sets its brightness, which is normalized and raised to the power,
+/// while the ratios between its channels are kept
+fn color_spectral(
+    draw_config: &DrawConfig,
+    channels: &Channels,
+    power: f64,
+) -> Result<image::RgbImage, EscapeError> {
+    let mut grids = Vec::with_capacity(3);
+    for name in SpectralConfig::CHANNELS {
+        grids.push(channels::channel(channels, name)?);
+    }
+    let (width, height) = grids[0].dimensions();
+    let brightness = |x, y| grids.iter().map(|g| g.value(x, y)).fold(0.0, f64::max);
+    let mut max = 0.0;
+    for x in 0..width {
+        for y in 0..height {
+            max = f64::max(max, brightness(x, y));
+        }
+    }
+
+    let mut result = image::RgbImage::new(width as u32, height as u32);
+    for x in 0..width {
+        for y in 0..height {
+            let pixel_max = brightness(x, y);
+            let scale = if pixel_max > 0.0 {
+                (pixel_max / max).powf(power) / pixel_max
+            } else {
+                0.0
+            };
+
+            let mut rgb = [0, 0, 0];
+            for i in 0..3 {
+                let value = draw_config.background_color[i] + grids[i].value(x, y) * scale;
+                rgb[i] = (value.clamp(0.0, 1.0) * 255.0) as u8;
+            }
+            result.put_pixel(x as u32, y as u32, image::Rgb(rgb));
+        }
+    }
+    info!("Spectral channels have been tone mapped");
+    Ok(result)
+}
+
+/// Draw where the orbits reaching the view started from, in white over the background
+fn color_c_density(
+    draw_config: &DrawConfig,
+    channels: &Channels,
+    power: f64,
+) -> Result<image::RgbImage, EscapeError> {
+    let grid = channels::channel(channels, C_DENSITY_CHANNEL)?;
+    let (width, height) = grid.dimensions();
+    let max = grid.data().iter().copied().fold(0.0, f64::max);
+
+    let mut result = image::RgbImage::new(width as u32, height as u32);
+    for x in 0..width {
+        for y in 0..height {
+            let value = if max > 0.0 {
+                (grid.value(x, y) / max).powf(power)
+            } else {
+                0.0
+            };
+
+            let rgb = draw_config
+                .background_color
+                .map(|channel| ((channel + value).clamp(0.0, 1.0) * 255.0) as u8);
+            result.put_pixel(x as u32, y as u32, image::Rgb(rgb));
+        }
+    }
+    info!("C density has been normalized");
+    Ok(result)
+}
+
+/// Hue, saturation and value in [0, 1] to rgb in [0, 1]
+fn hsv_to_rgb(hue: f64, saturation: f64, value: f64) -> [f64; 3] {
+    let h = (hue.rem_euclid(1.0)) * 6.0;
+    let c = value * saturation;
+    let x = c * (1.0 - ((h % 2.0) - 1.0).abs());
+    let m = value - c;
+    let (r, g, b) = match h as usize {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    [r + m, g + m, b + m]
+}
+
+/// The mean of a channel over the points recorded to each cell,
+/// normalized by the largest mean. Empty cells are 0
+fn normalized_mean(sums: &NormalizedGrid, counts: &CountGrid) -> NormalizedGrid {
+    let (width, height) = counts.dimensions();
+    let mut result = NormalizedGrid::zero(width, height);
+    let mut max = 0.0;
+    for x in 0..width {
+        for y in 0..height {
+            let count = counts.value(x, y);
+            if count > 0 {
+                let mean = sums.value(x, y) / count as f64;
+                max = f64::max(max, mean);
+                result.set_value(mean, x, y);
+            }
+        }
+    }
+
+    if max > 0.0 {
+        for x in 0..width {
+            for y in 0..height {
+                result.set_value(result.value(x, y) / max, x, y);
+            }
+        }
+    }
+    result
+}
+
+/// Color normalized grids according to the draw config
+pub fn color_grids(
     draw_config: &DrawConfig,
     sample_config: &SampleConfig,
     grids: &[NormalizedGrid],
@@ -1,4 +1,13 @@
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Width and height of the tiles in a TiledGrid
+const TILE_SIZE: usize = 64;
+
+/// Limits on tiled grids loaded from files, checked before any tiles are allocated
+const MAX_TILED_CELLS: usize = 1 << 32;
+const MAX_TILES: usize = 1 << 20;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Grid<N: Copy + Clone> {
@@ -20,6 +29,11 @@ impl<N: num_traits::Num + Copy + Clone> Grid<N> {
         let temp = self.boxes[y * self.width + x] + N::one();
         self.boxes[y * self.width + x] = temp;
     }
+
+    pub fn add_value(&mut self, value: N, x: usize, y: usize) {
+        let temp = self.boxes[y * self.width + x] + value;
+        self.boxes[y * self.width + x] = temp;
+    }
 }
 
 impl<N: Copy + Clone> Grid<N> {
@@ -37,6 +51,10 @@ impl<N: Copy + Clone> Grid<N> {
         self.boxes[y * self.width + x] = value;
     }
 
+    pub fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
     pub fn value(&self, x: usize, y: usize) -> N {
         self.boxes[y * self.width + x]
     }
@@ -46,6 +64,15 @@ impl<N: Copy + Clone> Grid<N> {
     }
 }
 
+impl<N: num_traits::Num + Copy + Clone> Grid<N> {
+    /// Add the values of other to this grid, other must have the same dimensions
+    pub fn add(&mut self, other: &Grid<N>) {
+        for (v, o) in self.boxes.iter_mut().zip(other.boxes.iter()) {
+            *v = *v + *o;
+        }
+    }
+}
+
 impl Grid<u64> {
     pub fn to_normalized_grid(&self) -> Grid<f64> {
         let mut max = 0;
@@ -73,3 +100,375 @@ impl Grid<u64> {
         result
     }
 }
+
+/// A grid of counts that many workers can increment at the same time
+#[derive(Debug)]
+pub struct AtomicGrid {
+    boxes: Vec<AtomicU64>,
+    width: usize,
+    height: usize,
+}
+
+impl AtomicGrid {
+    pub fn zero(width: usize, height: usize) -> AtomicGrid {
+        AtomicGrid {
+            boxes: (0..width * height).map(|_| AtomicU64::new(0)).collect(),
+            width,
+            height,
+        }
+    }
+
+    /// Returns true if the cell was empty before
+    pub fn increment(&self, x: usize, y: usize) -> bool {
+        self.boxes[y * self.width + x].fetch_add(1, Ordering::Relaxed) == 0
+    }
+
+    /// Take the current counts, leaving zeros behind
+    pub fn drain(&self) -> TiledGrid<u64> {
+        let mut result = TiledGrid::zero(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let value = self.boxes[y * self.width + x].swap(0, Ordering::Relaxed);
+                result.set_value(value, x, y);
+            }
+        }
+        result
+    }
+
+    /// Copy the current counts into a regular grid
+    pub fn to_grid(&self) -> Grid<u64> {
+        Grid {
+            boxes: self
+                .boxes
+                .iter()
+                .map(|b| b.load(Ordering::Relaxed))
+                .collect(),
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+/// A grid split into square tiles, where tiles are only allocated once written to.
+/// Only non-empty tiles are serialized.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(
+    try_from = "TiledGridRecord<N>",
+    bound(deserialize = "N: Copy + Clone + Deserialize<'de>")
+)]
+pub struct TiledGrid<N: Copy + Clone> {
+    tiles: Vec<Option<Box<[N]>>>,
+    tile_size: usize,
+    tiles_wide: usize,
+    width: usize,
+    height: usize,
+}
+
+impl<N: num_traits::Num + Copy + Clone> TiledGrid<N> {
+    pub fn zero(width: usize, height: usize) -> TiledGrid<N> {
+        let tiles_wide = width.div_ceil(TILE_SIZE);
+        let tiles_high = height.div_ceil(TILE_SIZE);
+        TiledGrid {
+            tiles: vec![None; tiles_wide * tiles_high],
+            tile_size: TILE_SIZE,
+            tiles_wide,
+            width,
+            height,
+        }
+    }
+
+    /// Find the tile index and the index within that tile for a coord
+    fn indices(&self, x: usize, y: usize) -> (usize, usize) {
+        let tile = (y / self.tile_size) * self.tiles_wide + x / self.tile_size;
+        let index = (y % self.tile_size) * self.tile_size + x % self.tile_size;
+        (tile, index)
+    }
+
+    fn tile_mut(&mut self, tile: usize) -> &mut [N] {
+        let tile_len = self.tile_size * self.tile_size;
+        self.tiles[tile].get_or_insert_with(|| vec![N::zero(); tile_len].into_boxed_slice())
+    }
+
+    pub fn increment(&mut self, x: usize, y: usize) {
+        let (tile, index) = self.indices(x, y);
+        let values = self.tile_mut(tile);
+        values[index] = values[index] + N::one();
+    }
+
+    pub fn set_value(&mut self, value: N, x: usize, y: usize) {
+        let (tile, index) = self.indices(x, y);
+        if value.is_zero() && self.tiles[tile].is_none() {
+            return;
+        }
+        self.tile_mut(tile)[index] = value;
+    }
+
+    pub fn value(&self, x: usize, y: usize) -> N {
+        let (tile, index) = self.indices(x, y);
+        match &self.tiles[tile] {
+            Some(values) => values[index],
+            None => N::zero(),
+        }
+    }
+
+    /// Add the values of other to this grid, other must have the same dimensions
+    pub fn add(&mut self, other: &TiledGrid<N>) {
+        if other.tile_size != self.tile_size {
+            for x in 0..self.width {
+                for y in 0..self.height {
+                    let sum = self.value(x, y) + other.value(x, y);
+                    self.set_value(sum, x, y);
+                }
+            }
+            return;
+        }
+
+        for (tile, other_values) in other.tiles.iter().enumerate() {
+            if let Some(other_values) = other_values {
+                let values = self.tile_mut(tile);
+                for (v, o) in values.iter_mut().zip(other_values.iter()) {
+                    *v = *v + *o;
+                }
+            }
+        }
+    }
+
+    /// Add the values of this grid to a dense grid with the same dimensions
+    pub fn add_to(&self, dense: &mut Grid<N>) {
+        for (tile, values) in self.tiles.iter().enumerate() {
+            if let Some(values) = values {
+                let tile_x = (tile % self.tiles_wide) * self.tile_size;
+                let tile_y = (tile / self.tiles_wide) * self.tile_size;
+                for (index, value) in values.iter().enumerate() {
+                    let x = tile_x + index % self.tile_size;
+                    let y = tile_y + index / self.tile_size;
+                    if x < self.width && y < self.height {
+                        let sum = dense.value(x, y) + *value;
+                        dense.set_value(sum, x, y);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The number of tiles that have been allocated, and the total number of tiles
+    pub fn tile_usage(&self) -> (usize, usize) {
+        let allocated = self.tiles.iter().filter(|t| t.is_some()).count();
+        (allocated, self.tiles.len())
+    }
+
+    pub fn to_dense(&self) -> Grid<N> {
+        let mut result = Grid::zero(self.width, self.height);
+        for x in 0..self.width {
+            for y in 0..self.height {
+                result.set_value(self.value(x, y), x, y);
+            }
+        }
+        result
+    }
+}
+
+/// How a TiledGrid is stored, only non-empty tiles are included
+#[derive(Deserialize)]
+struct TiledGridRecord<N> {
+    width: usize,
+    height: usize,
+    tile_size: usize,
+    tiles: Vec<TileRecord<N>>,
+}
+
+#[derive(Deserialize)]
+struct TileRecord<N> {
+    index: usize,
+    values: Vec<N>,
+}
+
+/// A TiledGridRecord borrowing its tiles, so saving a grid doesn't copy it
+#[derive(Serialize)]
+struct TiledGridRecordRef<'a, N> {
+    width: usize,
+    height: usize,
+    tile_size: usize,
+    tiles: Vec<TileRecordRef<'a, N>>,
+}
+
+#[derive(Serialize)]
+struct TileRecordRef<'a, N> {
+    index: usize,
+    values: &'a [N],
+}
+
+impl<N: num_traits::Num + Copy + Clone + Serialize> Serialize for TiledGrid<N> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let tiles = self
+            .tiles
+            .iter()
+            .enumerate()
+            .filter_map(|(index, values)| {
+                let values = values.as_deref()?;
+                values
+                    .iter()
+                    .any(|v| !v.is_zero())
+                    .then_some(TileRecordRef { index, values })
+            })
+            .collect();
+
+        TiledGridRecordRef {
+            width: self.width,
+            height: self.height,
+            tile_size: self.tile_size,
+            tiles,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<N: Copy + Clone> TryFrom<TiledGridRecord<N>> for TiledGrid<N> {
+    type Error = String;
+
+    fn try_from(record: TiledGridRecord<N>) -> Result<TiledGrid<N>, String> {
+        if record.tile_size == 0 {
+            return Err("Tile size must be greater than 0".to_string());
+        }
+        let cells = record.width.checked_mul(record.height);
+        if cells.is_none_or(|cells| cells > MAX_TILED_CELLS) {
+            return Err(format!(
+                "Grid of {}x{} is too large",
+                record.width, record.height
+            ));
+        }
+
+        let tiles_wide = record.width.div_ceil(record.tile_size);
+        let tiles_high = record.height.div_ceil(record.tile_size);
+        if tiles_wide * tiles_high > MAX_TILES {
+            return Err(format!(
+                "Tile size {} gives too many tiles",
+                record.tile_size
+            ));
+        }
+        let mut tiles = vec![None; tiles_wide * tiles_high];
+        for tile in record.tiles {
+            if tile.index >= tiles.len() {
+                return Err(format!("Tile index {} is out of bounds", tile.index));
+            }
+            if Some(tile.values.len()) != record.tile_size.checked_mul(record.tile_size) {
+                return Err(format!(
+                    "Tile {} has the wrong number of values",
+                    tile.index
+                ));
+            }
+            tiles[tile.index] = Some(tile.values.into_boxed_slice());
+        }
+
+        Ok(TiledGrid {
+            tiles,
+            tile_size: record.tile_size,
+            tiles_wide,
+            width: record.width,
+            height: record.height,
+        })
+    }
+}
+
+/// A histogram grid as stored in a histogram result, either dense or tiled.
+/// Files written before tiled grids existed load as dense grids.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum HistogramGrid {
+    Dense(Grid<u64>),
+    Tiled(TiledGrid<u64>),
+}
+
+impl HistogramGrid {
+    pub fn increment(&mut self, x: usize, y: usize) {
+        match self {
+            HistogramGrid::Dense(grid) => grid.increment(x, y),
+            HistogramGrid::Tiled(grid) => grid.increment(x, y),
+        }
+    }
+
+    pub fn value(&self, x: usize, y: usize) -> u64 {
+        match self {
+            HistogramGrid::Dense(grid) => grid.value(x, y),
+            HistogramGrid::Tiled(grid) => grid.value(x, y),
+        }
+    }
+
+    pub fn dimensions(&self) -> (usize, usize) {
+        match self {
+            HistogramGrid::Dense(grid) => (grid.width, grid.height),
+            HistogramGrid::Tiled(grid) => (grid.width, grid.height),
+        }
+    }
+
+    /// Add the counts of other to this grid, other must have the same dimensions
+    pub fn add(&mut self, other: &HistogramGrid) {
+        match (self, other) {
+            (HistogramGrid::Tiled(grid), HistogramGrid::Tiled(other)) => grid.add(other),
+            (HistogramGrid::Dense(grid), HistogramGrid::Tiled(other)) => other.add_to(grid),
+            (HistogramGrid::Dense(grid), HistogramGrid::Dense(other)) => grid.add(other),
+            (this, other) => {
+                let mut dense = this.to_dense();
+                dense.add(&other.to_dense());
+                *this = HistogramGrid::Dense(dense);
+            }
+        }
+    }
+
+    pub fn to_dense(&self) -> Grid<u64> {
+        match self {
+            HistogramGrid::Dense(grid) => grid.clone(),
+            HistogramGrid::Tiled(grid) => grid.to_dense(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiled_grid_allocates_lazily() {
+        let mut grid = TiledGrid::<u64>::zero(200, 100);
+        assert_eq!(grid.tile_usage(), (0, 8));
+
+        grid.increment(150, 70);
+        grid.increment(150, 70);
+        grid.set_value(0, 10, 10);
+        assert_eq!(grid.tile_usage(), (1, 8));
+        assert_eq!(grid.value(150, 70), 2);
+        assert_eq!(grid.value(10, 10), 0);
+
+        let dense = grid.to_dense();
+        assert_eq!(dense.value(150, 70), 2);
+        assert_eq!(dense.data().iter().sum::<u64>(), 2);
+    }
+
+    #[test]
+    fn histogram_grid_formats() {
+        let mut tiled = TiledGrid::<u64>::zero(100, 100);
+        tiled.increment(3, 4);
+        let json = serde_json::to_string(&HistogramGrid::Tiled(tiled)).unwrap();
+        match serde_json::from_str(&json).unwrap() {
+            HistogramGrid::Tiled(grid) => {
+                assert_eq!(grid.tile_usage(), (1, 4));
+                assert_eq!(grid.value(3, 4), 1);
+            }
+            HistogramGrid::Dense(_) => panic!("Tiled grid loaded as dense"),
+        }
+
+        let mut dense = Grid::<u64>::zero(2, 2);
+        dense.increment(1, 0);
+        let json = serde_json::to_string(&dense).unwrap();
+        match serde_json::from_str(&json).unwrap() {
+            HistogramGrid::Dense(grid) => assert_eq!(grid.value(1, 0), 1),
+            HistogramGrid::Tiled(_) => panic!("Dense grid loaded as tiled"),
+        }
+
+        let huge =
+            r#"{ "width": 18446744073709551615, "height": 2, "tile_size": 64, "tiles": [] }"#;
+        assert!(serde_json::from_str::<TiledGrid<u64>>(huge).is_err());
+        let tiny_tiles = r#"{ "width": 100000, "height": 100000, "tile_size": 1, "tiles": [] }"#;
+        assert!(serde_json::from_str::<TiledGrid<u64>>(tiny_tiles).is_err());
+    }
+}
@@ -1,36 +1,68 @@
+use crate::channels::Channels;
 use crate::config::SampleConfig;
-use crate::types::{CountGrid, EscapeError, EscapeResult};
+use crate::grid::HistogramGrid;
+use crate::spectrum::OrbitSpectrum;
+use crate::types::{EscapeError, EscapeResult};
 use serde::{Deserialize, Serialize};
 use std::io::{BufReader, BufWriter};
-use std::sync::Arc;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct HistogramResult {
-    config: SampleConfig,
-    grids: Vec<CountGrid>,
+    pub config: SampleConfig,
+    pub grids: Vec<HistogramGrid>,
+
+    /// Lengths of the orbits that were recorded, if the sampler kept track
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spectrum: Option<OrbitSpectrum>,
+
+    /// Float channels recorded alongside the counts, by name
+    #[serde(default, skip_serializing_if = "Channels::is_empty")]
+    pub channels: Channels,
+}
+
+/// Borrowed version of HistogramResult, so that saving doesn't copy the grids
+#[derive(Serialize)]
+struct HistogramResultRef<'a> {
+    config: &'a SampleConfig,
+    grids: &'a [HistogramGrid],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spectrum: Option<&'a OrbitSpectrum>,
+    #[serde(skip_serializing_if = "Channels::is_empty")]
+    channels: &'a Channels,
 }
 
 impl HistogramResult {
     pub fn save(
         config: &SampleConfig,
-        grids: &[CountGrid],
+        grids: &[HistogramGrid],
+        path: &std::path::Path,
+    ) -> EscapeResult {
+        HistogramResult::save_with(config, grids, None, &Channels::new(), path)
+    }
+
+    /// Save a result along with what sampling recorded besides the counts
+    pub fn save_with(
+        config: &SampleConfig,
+        grids: &[HistogramGrid],
+        spectrum: Option<&OrbitSpectrum>,
+        channels: &Channels,
         path: &std::path::Path,
     ) -> EscapeResult {
         let writer = BufWriter::new(std::fs::File::create(path)?);
         serde_json::to_writer(
             writer,
-            &HistogramResult {
-                config: config.clone(),
-                grids: grids.to_vec(),
+            &HistogramResultRef {
+                config,
+                grids,
+                spectrum,
+                channels,
             },
         )?;
         Ok(())
     }
-    pub fn from_file(
-        path: &std::path::Path,
-    ) -> Result<(Arc<SampleConfig>, Vec<CountGrid>), EscapeError> {
+
+    pub fn load(path: &std::path::Path) -> Result<HistogramResult, EscapeError> {
         let reader = BufReader::new(std::fs::File::open(path)?);
-        let result: HistogramResult = serde_json::from_reader(reader)?;
-        Ok((Arc::from(result.config), result.grids))
+        Ok(serde_json::from_reader(reader)?)
     }
 }
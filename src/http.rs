@@ -0,0 +1,197 @@
+use serde::Serialize;
+use std::future::Future;
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{trace, warn};
+
+use crate::sample::ArcSwitch;
+use crate::types::{EscapeError, EscapeResult};
+
+/// Requests with larger bodies than this are refused
+const MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+/// Requests with more header lines than this are refused
+const MAX_HEADERS: usize = 100;
+
+/// Just enough of an HTTP/1.1 request for our small APIs
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// The path split into its non-empty segments, without any query string
+    pub fn segments(&self) -> Vec<&str> {
+        let path = self.path.split('?').next().unwrap_or("");
+        path.split('/').filter(|s| !s.is_empty()).collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn json<T: Serialize>(status: u16, value: &T) -> Response {
+        match serde_json::to_vec(value) {
+            Ok(body) => Response {
+                status,
+                content_type: "application/json",
+                body,
+            },
+            Err(e) => Response::error(500, &format!("Failed to serialize response: {}", e)),
+        }
+    }
+
+    pub fn text(status: u16, text: String) -> Response {
+        Response {
+            status,
+            content_type: "text/plain; version=0.0.4; charset=utf-8",
+            body: text.into_bytes(),
+        }
+    }
+
+    /// Errors are reported as json, {"error": message}
+    pub fn error(status: u16, message: &str) -> Response {
+        #[derive(Serialize)]
+        struct ErrorBody<'a> {
+            error: &'a str,
+        }
+        Response::json(status, &ErrorBody { error: message })
+    }
+
+    pub fn not_found() -> Response {
+        Response::error(404, "Not found")
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Read one request from the stream, returns None if the connection closed first
+async fn read_request(
+    stream: &mut BufReader<&mut TcpStream>,
+) -> Result<Option<Request>, EscapeError> {
+    let mut request_line = String::new();
+    if stream.read_line(&mut request_line).await? == 0 {
+        return Ok(None);
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_string();
+    let path = parts.next().unwrap_or("/").to_string();
+
+    let mut content_length = 0;
+    for _ in 0..MAX_HEADERS {
+        let mut header = String::new();
+        if stream.read_line(&mut header).await? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+
+    if content_length > MAX_BODY_SIZE {
+        let msg = format!("Request body of {} bytes is too large", content_length);
+        return Err(EscapeError::Http(msg));
+    }
+
+    let mut body = vec![0; content_length];
+    stream.read_exact(&mut body).await?;
+    Ok(Some(Request { method, path, body }))
+}
+
+async fn write_response(stream: &mut TcpStream, response: &Response) -> EscapeResult {
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason_phrase(response.status),
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn handle_connection<F, Fut>(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    handler: F,
+) -> EscapeResult
+where
+    F: Fn(Request) -> Fut,
+    Fut: Future<Output = Response>,
+{
+    let request = {
+        let mut reader = BufReader::new(&mut stream);
+        read_request(&mut reader).await
+    };
+
+    let response = match request {
+        Ok(Some(request)) => {
+            trace!(%peer, method = %request.method, path = %request.path, "HTTP request");
+            handler(request).await
+        }
+        Ok(None) => return Ok(()),
+        Err(EscapeError::Http(msg)) => Response::error(413, &msg),
+        Err(e) => return Err(e),
+    };
+    write_response(&mut stream, &response).await
+}
+
+/// Answer requests with handler until the stop switch is triggered.
+/// Each connection serves a single request.
+pub async fn serve<F, Fut>(
+    listener: TcpListener,
+    handler: F,
+    stop_switch: ArcSwitch,
+) -> EscapeResult
+where
+    F: Fn(Request) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Response> + Send,
+{
+    let mut ticker = tokio::time::interval(std::time::Duration::from_millis(250));
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = accepted?;
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, peer, handler).await {
+                        warn!(%peer, "HTTP connection failed: {:?}", e);
+                    }
+                });
+            }
+            _ = ticker.tick() => {
+                if stop_switch.read().stop() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
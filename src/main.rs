@@ -16,6 +16,54 @@ mod types;
 /// Buddhabrot sampling implementation, and merging operation
 mod sample;
 
+/// Float channels accumulated alongside the counts
+mod channels;
+
+/// Grayscale masks that guide sampling
+mod mask;
+
+/// Counters kept by sampling workers
+mod stats;
+
+/// Periodic progress reports while sampling
+mod progress;
+
+/// Prometheus metrics for running samplers
+mod metrics;
+
+/// Snapshots of running samplers, taken on demand
+mod snapshot;
+
+/// Stopping sampling once the histogram has converged
+mod convergence;
+
+/// Metropolis-Hastings chain diagnostics
+mod diagnostics;
+
+/// Tuning sampler parameters with pilot runs
+mod tune;
+
+/// Orbit-length spectra, and proposing cutoffs from them
+mod spectrum;
+
+/// Memory estimates for sampling runs
+mod memory;
+
+/// Parallel tempering ladders for sampling workers
+mod tempering;
+
+/// Sampling with a coordinator and remote workers
+mod distributed;
+
+/// Minimal HTTP server for the job and metrics APIs
+mod http;
+
+/// Job server for running sample and draw jobs
+mod serve;
+
+/// Time-lapse recordings of sampling runs, and drawing them as frames
+mod timelapse;
+
 /// Implementation to draw buddhabrot histogram result
 mod draw;
 
@@ -39,6 +87,24 @@ fn main() -> EscapeResult {
         CliOptions::Report(report_options) => {
             sample::run_report(report_options)?;
         }
+        CliOptions::Coordinator(coordinator_options) => {
+            distributed::run_coordinator(coordinator_options)?;
+        }
+        CliOptions::Worker(worker_options) => {
+            distributed::run_worker(worker_options)?;
+        }
+        CliOptions::DrawTimelapse(draw_timelapse_options) => {
+            timelapse::run_draw_timelapse(draw_timelapse_options)?;
+        }
+        CliOptions::Tune(tune_options) => {
+            tune::run_tune(tune_options)?;
+        }
+        CliOptions::Cutoffs(cutoffs_options) => {
+            spectrum::run_cutoffs(cutoffs_options)?;
+        }
+        CliOptions::Serve(serve_options) => {
+            serve::run_serve(serve_options)?;
+        }
     }
     Ok(())
 }
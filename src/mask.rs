@@ -0,0 +1,124 @@
+use tracing::info;
+
+use crate::config::{MaskConfig, SampleConfig, ViewConfig};
+use crate::types::{EscapeError, EscapeResult, NormalizedGrid};
+
+/// A grayscale image aligned with the view, with values in [0, 1]
+#[derive(Debug)]
+pub struct Mask {
+    values: NormalizedGrid,
+}
+
+impl Mask {
+    /// Load the mask image, scaling it to the view if the sizes differ
+    /// Fails if the config pins a hash that the file no longer matches
+    pub fn load(config: &MaskConfig, view: &ViewConfig) -> Result<Mask, EscapeError> {
+        let bytes = std::fs::read(&config.path)?;
+        check_hash(config, &bytes)?;
+
+        let mut image = image::load_from_memory(&bytes)?.to_luma8();
+        let (width, height) = (view.width as u32, view.height as u32);
+        if image.dimensions() != (width, height) {
+            info!(
+                "Scaling mask {} from {:?} to {}x{}",
+                config.path.display(),
+                image.dimensions(),
+                width,
+                height
+            );
+            image = image::imageops::resize(
+                &image,
+                width,
+                height,
+                image::imageops::FilterType::Triangle,
+            );
+        }
+
+        let mut values = NormalizedGrid::zero(view.width, view.height);
+        for (x, y, pixel) in image.enumerate_pixels() {
+            values.set_value(pixel.0[0] as f64 / 255.0, x as usize, y as usize);
+        }
+        Ok(Mask { values })
+    }
+
+    pub fn value(&self, x: usize, y: usize) -> f64 {
+        self.values.value(x, y)
+    }
+}
+
+/// FNV-1a hash of a file's contents, as hex
+pub fn content_hash(bytes: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+fn check_hash(config: &MaskConfig, bytes: &[u8]) -> EscapeResult {
+    let hash = content_hash(bytes);
+    match &config.hash {
+        Some(expected) if *expected != hash => {
+            let msg = format!(
+                "Mask {} has hash {}, but the config expects {}",
+                config.path.display(),
+                hash,
+                expected
+            );
+            Err(EscapeError::InvalidSampleConfig(msg))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Record the hash of the mask file in the config, so results say exactly which mask they used
+/// If the config already has a hash, check the file still matches it
+pub fn pin_hash(config: &mut SampleConfig) -> EscapeResult {
+    if let Some(mask) = &mut config.mask {
+        let bytes = std::fs::read(&mask.path)?;
+        check_hash(mask, &bytes)?;
+        mask.hash = Some(content_hash(&bytes));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks_are_pinned() {
+        let path = std::env::temp_dir().join(format!("escape_mask_{}.png", std::process::id()));
+        let mut image = image::GrayImage::new(2, 2);
+        image.put_pixel(1, 0, image::Luma([255]));
+        image.save(&path).unwrap();
+
+        let mut config: SampleConfig = serde_json::from_str(
+            r#"{
+                "cutoffs": [20],
+                "view": { "width": 4, "height": 4, "zoom": 0.25, "center": [0.0, 0.0] }
+            }"#,
+        )
+        .unwrap();
+        config.mask = Some(MaskConfig {
+            path: path.clone(),
+            hash: None,
+        });
+        pin_hash(&mut config).unwrap();
+        let mask_config = config.mask.as_ref().unwrap();
+        assert_eq!(
+            mask_config.hash.as_deref(),
+            Some(content_hash(&std::fs::read(&path).unwrap()).as_str())
+        );
+
+        // The mask is scaled up to the view
+        let mask = Mask::load(mask_config, &config.view).unwrap();
+        assert_eq!(mask.value(3, 0), 1.0);
+        assert_eq!(mask.value(0, 3), 0.0);
+
+        config.mask.as_mut().unwrap().hash = Some("0".to_string());
+        assert!(pin_hash(&mut config).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+use tracing::warn;
+
+use crate::channels::ChannelGrids;
+use crate::config::SampleConfig;
+use crate::types::EscapeError;
+
+/// How workers store their histogram grids while sampling
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum GridStrategy {
+    /// A dense copy of the grids per worker, merged after sampling
+    Local,
+
+    /// A tiled copy of the grids per worker, merged after sampling
+    Tiled,
+
+    /// One set of grids shared by all workers
+    Shared,
+}
+
+/// Expected memory use of a sampling run, in bytes
+#[derive(Debug, Copy, Clone)]
+pub struct MemoryEstimate {
+    /// Histogram grids held while the workers are sampling
+    pub sampling_grids: u64,
+
+    /// Float channel grids, and with a mask the fractions of counts it carries per layer,
+    /// one set per worker whatever the grid strategy
+    pub channel_grids: u64,
+
+    /// Orbit buffers, one per worker
+    pub orbit_buffers: u64,
+
+    /// Additional grids allocated to merge the workers results, including their channels
+    pub merge_grids: u64,
+
+    /// Copies of the worker grids held while a snapshot is taken, along with their merge
+    /// Snapshots are taken on demand, for convergence checks, and for time-lapse frames
+    pub snapshot_grids: u64,
+}
+
+impl MemoryEstimate {
+    pub fn new(config: &SampleConfig, workers: usize, strategy: GridStrategy) -> MemoryEstimate {
+        let count_size = std::mem::size_of::<u64>() as u64;
+        let layers = config.layer_count() as u64;
+        let view = config.view;
+        let grid_size = count_size * (view.width * view.height) as u64;
+        let tiled_grid_size = {
+            // Worst case, every tile is allocated
+            let tile_size = 64;
+            let tiles = view.width.div_ceil(tile_size) * view.height.div_ceil(tile_size);
+            count_size * (tiles * tile_size * tile_size) as u64
+        };
+        let workers = workers as u64;
+
+        let (sampling_grids, merge_grids) = match strategy {
+            GridStrategy::Local => (workers * layers * grid_size, layers * grid_size),
+            GridStrategy::Tiled => (workers * layers * tiled_grid_size, layers * tiled_grid_size),
+            GridStrategy::Shared => (layers * grid_size, layers * grid_size),
+        };
+        // Each worker sends a copy of its grids, shared grids are copied once
+        let snapshot_grids = match strategy {
+            GridStrategy::Local | GridStrategy::Tiled => sampling_grids + merge_grids,
+            GridStrategy::Shared => layers * grid_size,
+        };
+
+        let float_size = std::mem::size_of::<f64>() as u64;
+        let channel_size = float_size * ChannelGrids::cell_count(config) as u64;
+        let carry_size = match config.mask {
+            Some(_) => layers * float_size * (view.width * view.height) as u64,
+            None => 0,
+        };
+
+        let complex_size = std::mem::size_of::<crate::types::Complex>() as u64;
+        let max_cutoff = config.max_iteration() as u64;
+        MemoryEstimate {
+            sampling_grids,
+            channel_grids: workers * (channel_size + carry_size),
+            orbit_buffers: workers * max_cutoff * complex_size,
+            merge_grids: merge_grids + channel_size,
+            snapshot_grids,
+        }
+    }
+
+    /// The most memory used at one time, which is while results are merged or a snapshot is taken
+    pub fn peak(&self) -> u64 {
+        self.sampling_grids
+            + self.channel_grids
+            + self.orbit_buffers
+            + self.merge_grids.max(self.snapshot_grids)
+    }
+}
+
+/// Check a run fits in a memory budget, falling back to shared grids if only they fit.
+/// Returns the strategy to sample with, and its estimate
+pub fn fit_budget(
+    config: &SampleConfig,
+    workers: usize,
+    strategy: GridStrategy,
+    budget: u64,
+) -> Result<(GridStrategy, MemoryEstimate), EscapeError> {
+    let estimate = MemoryEstimate::new(config, workers, strategy);
+    if estimate.peak() <= budget {
+        return Ok((strategy, estimate));
+    }
+
+    let shared_estimate = MemoryEstimate::new(config, workers, GridStrategy::Shared);
+    if shared_estimate.peak() <= budget {
+        warn!(
+            "Estimated peak memory exceeds budget of {}, using shared grids ({})",
+            format_bytes(budget),
+            format_bytes(shared_estimate.peak())
+        );
+        Ok((GridStrategy::Shared, shared_estimate))
+    } else {
+        Err(EscapeError::MemoryBudget(format!(
+            "Estimated peak memory {} exceeds budget of {}, even with shared grids ({})",
+            format_bytes(estimate.peak()),
+            format_bytes(budget),
+            format_bytes(shared_estimate.peak())
+        )))
+    }
+}
+
+/// Format a number of bytes for logging
+pub fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, units[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates() {
+        let config: SampleConfig = serde_json::from_str(
+            r#"{
+                "cutoffs": [10, 100],
+                "view": { "width": 100, "height": 50, "zoom": 0.25, "center": [0.0, 0.0] }
+            }"#,
+        )
+        .unwrap();
+
+        let local = MemoryEstimate::new(&config, 4, GridStrategy::Local);
+        assert_eq!(local.sampling_grids, 4 * 2 * 100 * 50 * 8);
+        assert_eq!(local.channel_grids, 0);
+        assert_eq!(local.orbit_buffers, 4 * 100 * 16);
+        assert_eq!(local.merge_grids, 2 * 100 * 50 * 8);
+        assert_eq!(local.snapshot_grids, 5 * 2 * 100 * 50 * 8);
+        assert_eq!(
+            local.peak(),
+            local.sampling_grids + local.orbit_buffers + local.snapshot_grids
+        );
+
+        let shared = MemoryEstimate::new(&config, 4, GridStrategy::Shared);
+        assert!(shared.peak() < local.peak());
+
+        let tiled = MemoryEstimate::new(&config, 4, GridStrategy::Tiled);
+        assert_eq!(tiled.sampling_grids, 4 * 2 * 128 * 64 * 8);
+        assert_eq!(format_bytes(3 << 29), "1.5 GiB");
+    }
+
+    #[test]
+    fn channel_estimates() {
+        let config: SampleConfig = serde_json::from_str(
+            r#"{
+                "cutoffs": [10, 100],
+                "view": { "width": 100, "height": 50, "zoom": 0.25, "center": [0.0, 0.0] },
+                "escape_channels": true,
+                "spectral": { "max_length": 100 },
+                "c_density": { "width": 20, "height": 10, "zoom": 0.5, "center": [0.0, 0.0] }
+            }"#,
+        )
+        .unwrap();
+
+        // 3 escape channels per layer, 3 spectral channels, and the c-density window
+        let channel_size = (9 * 100 * 50 + 20 * 10) * 8;
+        let shared = MemoryEstimate::new(&config, 4, GridStrategy::Shared);
+        assert_eq!(shared.channel_grids, 4 * channel_size);
+        assert_eq!(shared.merge_grids, 2 * 100 * 50 * 8 + channel_size);
+    }
+}
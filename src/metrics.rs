@@ -0,0 +1,160 @@
+use std::fmt::Write;
+use tokio::net::TcpListener;
+use tracing::info;
+
+use crate::config::SampleConfig;
+use crate::http::{self, Request, Response};
+use crate::sample::ArcSwitch;
+use crate::stats::{ArcStats, StatsSnapshot};
+use crate::types::EscapeResult;
+
+/// Name, help text, and value of a counter
+type Counter = (&'static str, &'static str, fn(&StatsSnapshot) -> u64);
+
+/// Write one metric family, with a sample per worker
+fn write_family<F: Fn(&StatsSnapshot) -> String>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    workers: &[StatsSnapshot],
+    value: F,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (worker, snapshot) in workers.iter().enumerate() {
+        let _ = writeln!(out, "{}{{worker=\"{}\"}} {}", name, worker, value(snapshot));
+    }
+}
+
+/// Render the worker counters in the Prometheus text format
+fn render_metrics(stats: &[ArcStats], config: &SampleConfig) -> String {
+    let workers: Vec<StatsSnapshot> = stats.iter().map(|s| s.snapshot()).collect();
+    let cells = (config.view.width * config.view.height * config.layer_count()) as f64;
+    let mut out = String::new();
+
+    let counters: [Counter; 8] = [
+        ("escape_samples_evaluated_total", "Orbits evaluated", |s| {
+            s.evaluated
+        }),
+        ("escape_samples_accepted_total", "Mutations accepted", |s| {
+            s.accepted
+        }),
+        ("escape_samples_rejected_total", "Mutations rejected", |s| {
+            s.rejected
+        }),
+        (
+            "escape_samples_outside_total",
+            "Mutations whose orbits missed the view",
+            |s| s.outside,
+        ),
+        (
+            "escape_orbits_escaped_total",
+            "Evaluated orbits that escaped",
+            |s| s.escaped,
+        ),
+        (
+            "escape_metro_instances_total",
+            "Metropolis-Hastings instances started",
+            |s| s.metro_instances,
+        ),
+        (
+            "escape_metro_failures_total",
+            "Metropolis-Hastings instances that ended early",
+            |s| s.metro_failures,
+        ),
+        (
+            "escape_initial_sample_failures_total",
+            "Searches for an initial sample that failed",
+            |s| s.initial_sample_failures,
+        ),
+    ];
+    for (name, help, value) in counters.iter() {
+        write_family(&mut out, name, "counter", help, &workers, |s| {
+            value(s).to_string()
+        });
+    }
+
+    write_family(
+        &mut out,
+        "escape_average_orbit_length",
+        "gauge",
+        "Average length of evaluated orbits",
+        &workers,
+        |s| s.average_orbit_length().to_string(),
+    );
+    write_family(
+        &mut out,
+        "escape_grid_fill_fraction",
+        "gauge",
+        "Fraction of histogram cells the worker was first to record to",
+        &workers,
+        |s| (s.filled_cells as f64 / cells).to_string(),
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP escape_layer_hits_total Orbit points recorded in view, per cutoff layer"
+    );
+    let _ = writeln!(out, "# TYPE escape_layer_hits_total counter");
+    for (worker, snapshot) in workers.iter().enumerate() {
+        for (layer, hits) in snapshot.layer_hits.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "escape_layer_hits_total{{worker=\"{}\",layer=\"{}\"}} {}",
+                worker, layer, hits
+            );
+        }
+    }
+
+    out
+}
+
+/// Serve the worker metrics on /metrics until the stop switch is triggered
+/// The listener is bound by the caller, so that a bad address is found before sampling starts
+pub async fn serve_metrics(
+    listener: TcpListener,
+    stats: Vec<ArcStats>,
+    config: SampleConfig,
+    stop_switch: ArcSwitch,
+) -> EscapeResult {
+    info!("Serving metrics on {}", listener.local_addr()?);
+
+    let handler = move |request: Request| {
+        let response = match (request.method.as_str(), request.segments().as_slice()) {
+            ("GET", ["metrics"]) => Response::text(200, render_metrics(&stats, &config)),
+            _ => Response::not_found(),
+        };
+        async move { response }
+    };
+    http::serve(listener, handler, stop_switch).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::WorkerStats;
+
+    #[test]
+    fn metrics_format() {
+        let config: SampleConfig = serde_json::from_str(
+            r#"{
+                "cutoffs": [20, 100],
+                "view": { "width": 10, "height": 10, "zoom": 0.25, "center": [0.0, 0.0] },
+                "samples": 500,
+                "warm_up_samples": 10
+            }"#,
+        )
+        .unwrap();
+        let stats = vec![WorkerStats::new(2, 100), WorkerStats::new(2, 100)];
+        WorkerStats::add(&stats[1].accepted, 7);
+        WorkerStats::add(&stats[1].filled_cells, 50);
+        WorkerStats::add(&stats[0].layer_hits[1], 3);
+
+        let text = render_metrics(&stats, &config);
+        assert!(text.contains("# TYPE escape_samples_accepted_total counter\n"));
+        assert!(text.contains("escape_samples_accepted_total{worker=\"1\"} 7\n"));
+        assert!(text.contains("escape_grid_fill_fraction{worker=\"1\"} 0.25\n"));
+        assert!(text.contains("escape_layer_hits_total{worker=\"0\",layer=\"1\"} 3\n"));
+    }
+}
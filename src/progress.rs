@@ -0,0 +1,164 @@
+use serde::Serialize;
+use std::io::Write;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use crate::sample::ArcSwitch;
+use crate::stats::{ArcStats, StatsSnapshot};
+use crate::types::EscapeResult;
+
+/// A single progress report, written as one json line
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ProgressReport {
+    pub elapsed_seconds: f64,
+    pub remaining_seconds: Option<f64>,
+    pub samples: u64,
+    pub samples_per_second: f64,
+    pub acceptance_ratio: f64,
+    pub rejection_ratio: f64,
+    pub outside_ratio: f64,
+    pub metro_instances: u64,
+    pub metro_failures: u64,
+    pub layer_hits: Vec<u64>,
+}
+
+impl ProgressReport {
+    /// The rate is measured since the previous report, the ratios over the whole run
+    fn new(
+        totals: &StatsSnapshot,
+        previous_samples: u64,
+        since_previous: Duration,
+        elapsed: Duration,
+        duration: Option<u64>,
+    ) -> ProgressReport {
+        let samples = totals.samples();
+        let ratio = |count: u64| {
+            if samples == 0 {
+                0.0
+            } else {
+                count as f64 / samples as f64
+            }
+        };
+        let seconds = since_previous.as_secs_f64();
+
+        ProgressReport {
+            elapsed_seconds: elapsed.as_secs_f64(),
+            remaining_seconds: duration
+                .map(|duration| (duration as f64 - elapsed.as_secs_f64()).max(0.0)),
+            samples,
+            samples_per_second: if seconds > 0.0 {
+                (samples - previous_samples) as f64 / seconds
+            } else {
+                0.0
+            },
+            acceptance_ratio: ratio(totals.accepted),
+            rejection_ratio: ratio(totals.rejected),
+            outside_ratio: ratio(totals.outside),
+            metro_instances: totals.metro_instances,
+            metro_failures: totals.metro_failures,
+            layer_hits: totals.layer_hits.clone(),
+        }
+    }
+
+    fn log(&self) {
+        let remaining = match self.remaining_seconds {
+            Some(seconds) => format!(", {:.0}s remaining", seconds),
+            None => String::new(),
+        };
+        info!(
+            "{:.0} samples/s, accepted {:.1}%, rejected {:.1}%, outside {:.1}%, metro instances {} ({} failed), layer hits {:?}{}",
+            self.samples_per_second,
+            self.acceptance_ratio * 100.0,
+            self.rejection_ratio * 100.0,
+            self.outside_ratio * 100.0,
+            self.metro_instances,
+            self.metro_failures,
+            self.layer_hits,
+            remaining,
+        );
+    }
+}
+
+/// Report the progress of the workers every interval until the stop switch is triggered
+/// Reports are logged, or written as json lines if an output file is given
+/// A final report is made once stopped
+pub async fn report_progress(
+    stats: Vec<ArcStats>,
+    interval: u64,
+    duration: Option<u64>,
+    mut output: Option<std::fs::File>,
+    stop_switch: ArcSwitch,
+) -> EscapeResult {
+    let start = Instant::now();
+    let mut previous = start;
+    let mut previous_samples = 0;
+    let mut stopped = false;
+    while !stopped {
+        // Check the switch often so the final report isn't delayed by a long interval
+        let next = previous + Duration::from_secs(interval);
+        while Instant::now() < next {
+            if stop_switch.read().stop() {
+                stopped = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        let now = Instant::now();
+        let totals = StatsSnapshot::total(&stats);
+        if stopped && previous != start && totals.samples() == previous_samples {
+            break;
+        }
+        let report = ProgressReport::new(
+            &totals,
+            previous_samples,
+            now - previous,
+            now - start,
+            duration,
+        );
+        previous = now;
+        previous_samples = report.samples;
+
+        match &mut output {
+            Some(file) => {
+                serde_json::to_writer(&mut *file, &report)?;
+                writeln!(file)?;
+                file.flush()?;
+            }
+            None => report.log(),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_report() {
+        let totals = StatsSnapshot {
+            accepted: 600,
+            rejected: 300,
+            outside: 100,
+            metro_instances: 4,
+            metro_failures: 1,
+            layer_hits: vec![10, 20],
+            ..StatsSnapshot::default()
+        };
+        let report = ProgressReport::new(
+            &totals,
+            200,
+            Duration::from_secs(2),
+            Duration::from_secs(10),
+            Some(60),
+        );
+        assert_eq!(report.samples, 1000);
+        assert_eq!(report.samples_per_second, 400.0);
+        assert_eq!(report.acceptance_ratio, 0.6);
+        assert_eq!(report.outside_ratio, 0.1);
+        assert_eq!(report.remaining_seconds, Some(50.0));
+        assert_eq!(report.layer_hits, vec![10, 20]);
+    }
+}
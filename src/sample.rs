@@ -1,31 +1,68 @@
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use rand::distributions::Distribution;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::io::BufReader;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
 use tracing::{error, info, trace, warn};
 
+use crate::channels::{self, layer_channel, ChannelGrids, Channels};
 use crate::cli_options::{MergeOptions, ReportOptions, SampleOptions};
-use crate::config::{SampleConfig, ViewConfig};
+use crate::config::{
+    CDomain, DrawConfig, OrbitTrapConfig, SampleConfig, SpectralConfig, ViewConfig, Weighting,
+    C_DENSITY_CHANNEL, ESCAPE_CHANNELS,
+};
+use crate::convergence::{self, stop_on_convergence};
+use crate::diagnostics::{ChainTrace, Diagnostics, MetroOutcome};
+use crate::grid::{AtomicGrid, HistogramGrid};
 use crate::histogram_result::HistogramResult;
-use crate::types::{Complex, CountGrid, EscapeError, EscapeResult};
+use crate::mask::{self, Mask};
+use crate::memory::{self, format_bytes, GridStrategy, MemoryEstimate};
+use crate::metrics::serve_metrics;
+use crate::progress::report_progress;
+use crate::snapshot::{snapshot_on_demand, user_signal, SnapshotOutputs};
+use crate::spectrum::OrbitSpectrum;
+use crate::stats::{ArcStats, StatsSnapshot, WorkerStats};
+use crate::tempering::{ChainState, Exchange, TemperingRung};
+use crate::timelapse::{record_timelapse, TimelapseWriter};
+use crate::types::{
+    Complex, CountGrid, EscapeError, EscapeResult, MemorySize, NormalizedGrid, TiledCountGrid,
+};
 
 /// Randomly sample a complex number with a norm less than radius
-fn radius_sample(radius: f64) -> Complex {
-    let mut rng = rand::thread_rng();
+fn radius_sample<R: Rng>(rng: &mut R, radius: f64) -> Complex {
     let range = rand::distributions::Uniform::from(-radius..radius);
     let rad_sqr = radius * radius;
     loop {
-        let c = Complex::new(range.sample(&mut rng), range.sample(&mut rng));
+        let c = Complex::new(range.sample(rng), range.sample(rng));
         if c.norm_sqr() < rad_sqr {
             return c;
         }
     }
 }
 
+/// Rejection sampling from a c domain's bounds gives up after this many attempts
+const MAX_DOMAIN_ATTEMPTS: usize = 10_000;
+
+/// Uniform sample from a c domain, by rejection from its bounds
+/// None if the domain covers too little of its bounds to hit in MAX_DOMAIN_ATTEMPTS
+fn domain_sample<R: Rng>(rng: &mut R, domain: &CDomain) -> Option<Complex> {
+    let (min, max) = domain.bounds();
+    let re = rand::distributions::Uniform::from(min.re..=max.re);
+    let im = rand::distributions::Uniform::from(min.im..=max.im);
+    (0..MAX_DOMAIN_ATTEMPTS)
+        .map(|_| Complex::new(re.sample(rng), im.sample(rng)))
+        .find(|c| domain.contains(c))
+}
+
 /// Random sample from [0..1)
-fn random_prob() -> f64 {
-    let mut rng = rand::thread_rng();
-    rand::distributions::Uniform::from(0.0..1.0).sample(&mut rng)
+fn random_prob<R: Rng>(rng: &mut R) -> f64 {
+    rand::distributions::Uniform::from(0.0..1.0).sample(rng)
 }
 
 /// Find the grid coords for a given complex number and view config
@@ -50,17 +87,20 @@ fn project_onto_view(view: &ViewConfig, c: &Complex) -> Option<(usize, usize)> {
 
 /// Utility used to synchronize workers
 #[derive(Debug)]
-struct StopSwitch {
+pub struct StopSwitch {
     stop: bool,
 }
-type ArcSwitch = Arc<RwLock<StopSwitch>>;
+pub type ArcSwitch = Arc<RwLock<StopSwitch>>;
 
 impl StopSwitch {
-    async fn new(maybe_duration: &Option<u64>) -> ArcSwitch {
-        let result = Arc::new(RwLock::new(StopSwitch { stop: false }));
+    pub async fn new(maybe_duration: &Option<u64>) -> ArcSwitch {
+        let result = StopSwitch::without_handlers();
 
         tokio::spawn(ctrl_c_handler(result.clone()));
 
+        #[cfg(unix)]
+        tokio::spawn(sigterm_handler(result.clone()));
+
         if let Some(seconds) = maybe_duration {
             tokio::spawn(duration_handler(result.clone(), *seconds));
         }
@@ -68,70 +108,286 @@ impl StopSwitch {
         result
     }
 
-    fn stop(&self) -> bool {
+    /// A switch that is only triggered explicitly
+    pub fn without_handlers() -> ArcSwitch {
+        Arc::new(RwLock::new(StopSwitch { stop: false }))
+    }
+
+    pub fn stop(&self) -> bool {
         self.stop
     }
+
+    pub fn trigger(&mut self, reason: &str) {
+        if self.stop {
+            error!("Stop Switch already triggered");
+        } else {
+            info!("{}", reason);
+            self.stop = true;
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn sigterm_handler(switch: ArcSwitch) -> EscapeResult {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigterm = signal(SignalKind::terminate())?;
+    loop {
+        sigterm.recv().await;
+        switch.write().trigger("SIGTERM received!");
+    }
 }
 
 async fn ctrl_c_handler(switch: ArcSwitch) -> EscapeResult {
     loop {
         tokio::signal::ctrl_c().await?;
-        let mut s = switch.write();
-        if s.stop {
-            error!("Stop Switch already triggered");
+        switch.write().trigger("CTRL C pressed!");
+    }
+}
+
+pub async fn duration_handler(switch: ArcSwitch, seconds: u64) -> EscapeResult {
+    tokio::time::sleep(std::time::Duration::from_secs(seconds)).await;
+    switch.write().trigger("Duration complete");
+    Ok(())
+}
+
+/// The grids a worker records orbits to
+#[derive(Debug)]
+enum WorkerGrids {
+    /// Each worker has its own copy, merged once sampling is complete
+    Local(Vec<HistogramGrid>),
+
+    /// All workers record to the same grids
+    Shared(Arc<Vec<AtomicGrid>>),
+}
+
+impl WorkerGrids {
+    fn local(sample_config: &SampleConfig, tiled: bool) -> WorkerGrids {
+        let view = sample_config.view;
+        let grid = if tiled {
+            HistogramGrid::Tiled(TiledCountGrid::zero(view.width, view.height))
         } else {
-            info!("CTRL C pressed!");
-            s.stop = true;
+            HistogramGrid::Dense(CountGrid::zero(view.width, view.height))
+        };
+        WorkerGrids::Local(vec![grid; sample_config.layer_count()])
+    }
+
+    fn shared(sample_config: &SampleConfig) -> Arc<Vec<AtomicGrid>> {
+        let view = sample_config.view;
+        Arc::new(
+            (0..sample_config.layer_count())
+                .map(|_| AtomicGrid::zero(view.width, view.height))
+                .collect(),
+        )
+    }
+
+    /// Returns true if the cell was empty before
+    fn increment(&mut self, layer: usize, x: usize, y: usize) -> bool {
+        match self {
+            WorkerGrids::Local(grids) => {
+                let empty = grids[layer].value(x, y) == 0;
+                grids[layer].increment(x, y);
+                empty
+            }
+            WorkerGrids::Shared(grids) => grids[layer].increment(x, y),
         }
     }
 }
 
-async fn duration_handler(switch: ArcSwitch, seconds: u64) -> EscapeResult {
-    tokio::time::sleep(std::time::Duration::from_secs(seconds)).await;
-    let mut s = switch.write();
-    if s.stop {
-        error!("Stop Switch already triggered");
-    } else {
-        info!("Duration complete");
-        s.stop = true;
+/// Workers with a budget stop after this many metro instances in a row fail without sampling
+const MAX_FAILED_INSTANCES: usize = 1000;
+
+/// Limits on the work done by a run, split between its workers
+/// Samples are counted after warm up, and every proposal counts whether or not it lands in view
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct WorkBudget {
+    pub samples: Option<u64>,
+    pub metro_instances: Option<u64>,
+}
+
+impl WorkBudget {
+    /// Each worker gets an even share, with the remainder going to the first workers
+    fn share(&self, worker: usize, workers: usize) -> WorkBudget {
+        let split = |total: u64| {
+            let workers = workers as u64;
+            total / workers + ((worker as u64) < total % workers) as u64
+        };
+        WorkBudget {
+            samples: self.samples.map(split),
+            metro_instances: self.metro_instances.map(split),
+        }
     }
-    Ok(())
+
+    fn is_limited(&self) -> bool {
+        self.samples.is_some() || self.metro_instances.is_some()
+    }
+}
+
+/// Lets a sampler ask its workers for copies of their grids while they run
+/// Workers answer whenever they see a new epoch, hot tempering chains answer with no grids
+#[derive(Debug)]
+struct SnapshotRequests {
+    epoch: AtomicU64,
+    sender: mpsc::UnboundedSender<(u64, Vec<HistogramGrid>)>,
 }
 
 #[derive(Debug)]
 struct WorkerState {
     sample_config: SampleConfig,
-    grids: Vec<CountGrid>,
+    grids: WorkerGrids,
     norm_cutoff_sqr: f64,
     iteration_cutoff: usize,
     iteration_cutoff_f64: f64,
+    layer_ranges: Vec<RangeInclusive<usize>>,
     orbit_buffer: Vec<Complex>,
+
+    /// Where the last evaluated orbit went once it escaped
+    escaped_z: Complex,
+    channels: ChannelGrids,
+
+    /// Index of the first layer's weight channel, the other layers follow
+    weight_channel: Option<usize>,
+
+    /// Indices of the first layer's escape channels, and their values for the current orbit
+    escape_channels: Option<[usize; 3]>,
+    escape_values: [f64; 3],
+
+    /// Index of the red spectral channel, green and blue follow
+    spectral_channel: Option<usize>,
+
+    /// Index of the first layer's trap distance channel, the other layers follow
+    trap_channel: Option<usize>,
+
+    /// Index of the c density channel
+    c_density_channel: Option<usize>,
+
+    /// Optional mask, and per layer the fractions of counts it left over in each cell
+    mask: Option<Arc<Mask>>,
+    mask_carry: Vec<NormalizedGrid>,
     stop_switch: ArcSwitch,
+    tempering: Option<TemperingRung>,
+    rng: StdRng,
+    stats: ArcStats,
+    snapshots: Option<Arc<SnapshotRequests>>,
+    snapshot_epoch: u64,
+    budget: WorkBudget,
+    samples_taken: u64,
+
+    /// The number of workers still working through their budgets
+    budget_workers: Option<Arc<AtomicUsize>>,
+    diagnostics: Option<(ChainTrace, Arc<Mutex<Diagnostics>>)>,
 }
 
 impl WorkerState {
-    fn new(sample_config: &SampleConfig, stop_switch: ArcSwitch) -> WorkerState {
-        let cutoff = *sample_config.cutoffs.last().unwrap();
-        let view = sample_config.view;
+    fn new(
+        sample_config: &SampleConfig,
+        grids: WorkerGrids,
+        stop_switch: ArcSwitch,
+        tempering: Option<TemperingRung>,
+        seed: Option<u64>,
+        stats: ArcStats,
+        snapshots: Option<Arc<SnapshotRequests>>,
+    ) -> WorkerState {
+        let cutoff = sample_config.max_iteration();
+        let channels = ChannelGrids::new(sample_config);
+        let weight_channel = channels.index(&layer_channel(Weighting::CHANNEL, 0));
+        let escape_channels = ESCAPE_CHANNELS
+            .iter()
+            .map(|name| channels.index(&layer_channel(name, 0)))
+            .collect::<Option<Vec<usize>>>()
+            .map(|indices| [indices[0], indices[1], indices[2]]);
+        let spectral_channel = channels.index(SpectralConfig::CHANNELS[0]);
+        let trap_channel = channels.index(&layer_channel(OrbitTrapConfig::CHANNEL, 0));
+        let c_density_channel = channels.index(C_DENSITY_CHANNEL);
         WorkerState {
             sample_config: sample_config.clone(),
-            grids: vec![CountGrid::zero(view.width, view.height); sample_config.cutoffs.len()],
+            grids,
             norm_cutoff_sqr: sample_config.norm_cutoff * sample_config.norm_cutoff,
             iteration_cutoff: cutoff,
             iteration_cutoff_f64: cutoff as f64,
+            layer_ranges: sample_config.layer_ranges(),
             orbit_buffer: Vec::with_capacity(cutoff),
+            escaped_z: Complex::new(0.0, 0.0),
+            channels,
+            weight_channel,
+            escape_channels,
+            escape_values: [0.0; 3],
+            spectral_channel,
+            trap_channel,
+            c_density_channel,
+            mask: None,
+            mask_carry: Vec::new(),
             stop_switch,
+            tempering,
+            rng: match seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            },
+            stats,
+            snapshots,
+            snapshot_epoch: 0,
+            budget: WorkBudget::default(),
+            samples_taken: 0,
+            budget_workers: None,
+            diagnostics: None,
+        }
+    }
+
+    /// Limit the work this worker does, the last worker to finish its budget stops the run
+    fn with_budget(mut self, budget: WorkBudget, budget_workers: Arc<AtomicUsize>) -> Self {
+        self.budget = budget;
+        self.budget_workers = Some(budget_workers);
+        self
+    }
+
+    /// Weight contributions and recording by a mask shared by the run's workers
+    fn with_mask(mut self, mask: Arc<Mask>) -> Self {
+        let view = self.sample_config.view;
+        self.mask = Some(mask);
+        self.mask_carry =
+            vec![NormalizedGrid::zero(view.width, view.height); self.layer_ranges.len()];
+        self
+    }
+
+    /// Collect chain diagnostics into a summary shared by the run's workers
+    fn with_diagnostics(mut self, diagnostics: Arc<Mutex<Diagnostics>>) -> Self {
+        self.diagnostics = Some((ChainTrace::default(), diagnostics));
+        self
+    }
+
+    fn trace_step(&mut self, contrib: f64, accepted: bool) {
+        if let Some((trace, _)) = &mut self.diagnostics {
+            trace.step(contrib, accepted);
         }
     }
 
+    fn samples_spent(&self) -> bool {
+        self.budget
+            .samples
+            .is_some_and(|max| self.samples_taken >= max)
+    }
+
+    fn budget_spent(&self, metro_instances: u64) -> bool {
+        self.samples_spent()
+            || self
+                .budget
+                .metro_instances
+                .is_some_and(|max| metro_instances >= max)
+    }
+
     fn project(&self, c: &Complex) -> Option<(usize, usize)> {
         project_onto_view(&self.sample_config.view, c)
     }
 
     /// Typical mandelbrot code, though we do fill the orbit buffer with the iterations
     /// Returns true if the sample escaped
+    /// Samples outside the c domain are rejected as if they had not escaped
     fn evaluate(&mut self, sample: &Complex) -> bool {
         self.orbit_buffer.clear();
+        if let Some(c_domain) = &self.sample_config.c_domain {
+            if !c_domain.contains(sample) {
+                return false;
+            }
+        }
         let jc = self.sample_config.julia_set_param;
         let m = self.sample_config.mandelbrot_param;
         let c = jc + m * sample;
@@ -144,50 +400,271 @@ impl WorkerState {
         }
 
         // Did point escape?
-        z.norm_sqr() > self.norm_cutoff_sqr
+        let escaped = z.norm_sqr() > self.norm_cutoff_sqr;
+        self.escaped_z = z;
+        WorkerStats::add(&self.stats.evaluated, 1);
+        WorkerStats::add(&self.stats.escaped, escaped as u64);
+        WorkerStats::add(&self.stats.orbit_iterations, iteration as u64);
+        escaped
     }
 
     /// The contribution of a proposed value c
     /// is the fraction of the orbit that intersects the view
-    fn contribution(&self, intersection_count: usize) -> f64 {
-        intersection_count as f64 / self.iteration_cutoff as f64
+    /// With a mask, the intersections are replaced by the mask's sum over them
+    fn contribution(&self, intersection_count: usize, mask_weight: Option<f64>) -> f64 {
+        let intersections = mask_weight.unwrap_or(intersection_count as f64);
+        intersections / self.iteration_cutoff as f64
+    }
+
+    /// The value of the mask at a cell, 1.0 without a mask
+    fn mask_value(&self, x: usize, y: usize) -> f64 {
+        self.mask.as_ref().map_or(1.0, |mask| mask.value(x, y))
+    }
+
+    /// The sum of the mask over the recorded part of the orbit buffer, None without a mask
+    fn mask_weight(&self) -> Option<f64> {
+        let mask = self.mask.as_ref()?;
+        let Some((start, stride)) = self.orbit_window() else {
+            return Some(0.0);
+        };
+        let weight = self.orbit_buffer[start..]
+            .iter()
+            .step_by(stride)
+            .filter_map(|c| self.project(c))
+            .map(|(x, y)| mask.value(x, y))
+            .sum();
+        Some(weight)
     }
 
     /// Find the number of times the orbit buffer intersects the view
     /// without modifying the counts
     /// This is useful when finding samples or warming up the sampling routine
+    /// Orbits that no layer records, that miss the orbit traps,
+    /// or that only cross black parts of the mask have no intersections
     #[tracing::instrument(skip(self))]
     fn orbit_intersections(&mut self) -> usize {
+        if !self.orbit_in_layers() {
+            return 0;
+        }
+        let Some((start, stride)) = self.orbit_window() else {
+            return 0;
+        };
         let mut result = 0;
-        for c in &self.orbit_buffer {
-            if self.project(c).is_some() {
-                result += 1;
+        for c in self.orbit_buffer[start..].iter().step_by(stride) {
+            if let Some((x, y)) = self.project(c) {
+                if self.mask_value(x, y) > 0.0 {
+                    result += 1;
+                }
             }
         }
         result
     }
 
+    /// The start and stride of the part of the orbit buffer that gets recorded
+    /// None if the orbit misses the orbit traps, and is not recorded at all
+    fn orbit_window(&self) -> Option<(usize, usize)> {
+        let len = self.orbit_buffer.len();
+        let mut start = self.sample_config.skip_iterations.min(len);
+        if let Some(last) = self.sample_config.last_iterations {
+            start = start.max(len.saturating_sub(last));
+        }
+        if let Some(orbit_traps) = &self.sample_config.orbit_traps {
+            let hit = self
+                .orbit_buffer
+                .iter()
+                .position(|z| orbit_traps.traps(z))?;
+            if orbit_traps.after_hit {
+                start = start.max(hit);
+            }
+        }
+        Some((start, self.sample_config.iteration_stride))
+    }
+
+    /// Whether any layer records orbits of the orbit buffer's length
+    fn orbit_in_layers(&self) -> bool {
+        let len = self.orbit_buffer.len();
+        self.layer_ranges.iter().any(|range| range.contains(&len))
+    }
+
+    /// Record one point of the orbit buffer to a layer's count grid and the channels,
+    /// weighted by the mask's value at its cell
+    /// Mirrored points come from the conjugate orbit
+    /// Returns true if the cell was empty before
+    fn record_point(
+        &mut self,
+        layer: usize,
+        index: usize,
+        x: usize,
+        y: usize,
+        mirrored: bool,
+    ) -> bool {
+        let scale = self.mask_value(x, y);
+        if scale == 0.0 {
+            return false;
+        }
+        if let (Some(weighting), Some(channel)) =
+            (self.sample_config.weighting, self.weight_channel)
+        {
+            let weight = self.point_weight(weighting, index);
+            self.channels.add(channel + layer, weight * scale, x, y);
+        }
+        if let Some(channel) = self.trap_channel {
+            let orbit_traps = self.sample_config.orbit_traps.as_ref().unwrap();
+            let distance = orbit_traps.distance(&self.orbit_buffer[index]);
+            self.channels.add(channel + layer, distance * scale, x, y);
+        }
+        if let Some([cos, sin, time]) = self.escape_channels {
+            let [escape_cos, escape_sin, escape_time] = self.escape_values;
+            // The conjugate orbit escapes at the mirrored angle
+            let escape_sin = if mirrored { -escape_sin } else { escape_sin };
+            self.channels.add(cos + layer, escape_cos * scale, x, y);
+            self.channels.add(sin + layer, escape_sin * scale, x, y);
+            self.channels.add(time + layer, escape_time * scale, x, y);
+        }
+        self.increment_count(layer, x, y, scale)
+    }
+
+    /// Add a weight of at most 1 to a layer's count grid
+    /// Counts are whole, so fractions are carried in the cell until they add up to a count
+    /// Returns true if the cell was empty before
+    fn increment_count(&mut self, layer: usize, x: usize, y: usize, weight: f64) -> bool {
+        if weight < 1.0 {
+            let carry = &mut self.mask_carry[layer];
+            let total = carry.value(x, y) + weight;
+            if total < 1.0 {
+                carry.set_value(total, x, y);
+                return false;
+            }
+            carry.set_value(total - 1.0, x, y);
+        }
+        self.grids.increment(layer, x, y)
+    }
+
+    /// The cosine and sine of the angle the last orbit escaped at, and its smooth escape time
+    /// The smooth escape time is the usual continuous iteration count
+    fn escape_values(&self) -> [f64; 3] {
+        let norm = self.escaped_z.norm_sqr().sqrt();
+        if norm == 0.0 {
+            return [0.0; 3];
+        }
+        let len = self.orbit_buffer.len() as f64;
+        let time = if norm > 1.0 {
+            len + 1.0 - norm.ln().log2()
+        } else {
+            len
+        };
+        [self.escaped_z.re / norm, self.escaped_z.im / norm, time]
+    }
+
+    /// The weight of a point in the orbit buffer
+    fn point_weight(&self, weighting: Weighting, index: usize) -> f64 {
+        let len = self.orbit_buffer.len();
+        match weighting {
+            Weighting::IterationIndex => index as f64 / len as f64,
+            Weighting::Norm => self.orbit_buffer[index].norm_sqr().sqrt(),
+            Weighting::StepLength => {
+                let next = self.orbit_buffer.get(index + 1).unwrap_or(&self.escaped_z);
+                (next - self.orbit_buffer[index]).norm_sqr().sqrt()
+            }
+            Weighting::OrbitLength { exponent } => (len as f64).powf(exponent),
+        }
+    }
+
     /// Record the contents of the orbit buffer to the count grids
     /// Return the number of intersections
+    /// The orbit goes to the first layer whose range contains it,
+    /// or to all of them with cumulative layers. Either way its intersections count once.
+    /// Only the part of the orbit inside the configured window is recorded.
+    /// With symmetry enabled the conjugate orbit is recorded too,
+    /// but it does not add to the returned intersections
     #[tracing::instrument(skip(self))]
     fn record_orbit(&mut self) -> usize {
         let mut result = 0;
-        for (i, cutoff) in self.sample_config.cutoffs.iter().enumerate() {
-            if self.orbit_buffer.len() <= *cutoff {
-                for c in &self.orbit_buffer {
-                    if let Some((x, y)) = self.project(c) {
-                        self.grids[i].increment(x, y);
-                        result += 1;
+        let mut filled = 0;
+        let height = self.sample_config.view.height;
+        let len = self.orbit_buffer.len();
+        let Some((start, stride)) = self.orbit_window() else {
+            return 0;
+        };
+        if self.escape_channels.is_some() {
+            self.escape_values = self.escape_values();
+        }
+        for i in 0..self.layer_ranges.len() {
+            if !self.layer_ranges[i].contains(&len) {
+                continue;
+            }
+
+            let mut hits = 0;
+            for index in (start..len).step_by(stride) {
+                let c = self.orbit_buffer[index];
+                if let Some((x, y)) = self.project(&c) {
+                    filled += self.record_point(i, index, x, y, false) as u64;
+                    hits += (self.mask_value(x, y) > 0.0) as usize;
+                    if self.sample_config.symmetry {
+                        filled += self.record_point(i, index, x, height - 1 - y, true) as u64;
                     }
                 }
-                return result;
+            }
+            WorkerStats::add(&self.stats.layer_hits[i], hits as u64);
+            result = hits;
+
+            if !self.sample_config.cumulative_layers {
+                break;
+            }
+        }
+
+        if result > 0 {
+            let bin = OrbitSpectrum::bin(len);
+            WorkerStats::add(&self.stats.spectrum[bin], result as u64);
+            self.record_spectral(start, stride);
+        }
+        WorkerStats::add(&self.stats.filled_cells, filled);
+        result
+    }
+
+    /// Add an accepted sample's hits in the view to the c density window,
+    /// with symmetry its conjugate was recorded too
+    fn record_c_density(&mut self, sample: &Complex, hits: usize) {
+        let (Some(window), Some(channel)) = (&self.sample_config.c_density, self.c_density_channel)
+        else {
+            return;
+        };
+        let hits = hits as f64;
+        if let Some((x, y)) = project_onto_view(window, sample) {
+            self.channels.add(channel, hits, x, y);
+        }
+        if self.sample_config.symmetry {
+            if let Some((x, y)) = project_onto_view(window, &sample.conj()) {
+                self.channels.add(channel, hits, x, y);
+            }
+        }
+    }
+
+    /// Add the color of the orbit buffer's length to the spectral channels
+    /// This happens once per point, however many layers the orbit went to
+    fn record_spectral(&mut self, start: usize, stride: usize) {
+        let (Some(spectral), Some(channel)) = (&self.sample_config.spectral, self.spectral_channel)
+        else {
+            return;
+        };
+        let color = spectral.color(self.orbit_buffer.len());
+        let height = self.sample_config.view.height;
+        for index in (start..self.orbit_buffer.len()).step_by(stride) {
+            let c = self.orbit_buffer[index];
+            let mut cells = [None, None];
+            if let Some((x, y)) = self.project(&c) {
+                cells[0] = Some((x, y));
+                if self.sample_config.symmetry {
+                    cells[1] = Some((x, height - 1 - y));
+                }
+            }
+            for &(x, y) in cells.iter().flatten() {
+                let scale = self.mask_value(x, y);
+                for (i, value) in color.iter().enumerate() {
+                    self.channels.add(channel + i, *value * scale, x, y);
+                }
             }
         }
-        panic!(
-            "Did not record orbit to cutoff, ob len() {}, cutoff {}",
-            self.orbit_buffer.len(),
-            self.sample_config.cutoffs.last().unwrap()
-        );
     }
 
     /// Find a point whose orbit passes through the view
@@ -196,7 +673,15 @@ impl WorkerState {
     /// Per the comment in his code, better than random sampling for higher zooms
     #[tracing::instrument(skip(self))]
     fn find_initial_sample(&mut self) -> Option<Complex> {
-        let (result, depth) = self.find_initial_sample_r(&Complex::new(0.0, 0.0), 2.0, 0);
+        // With a c domain, the search starts out covering its bounds
+        let (center, radius) = match &self.sample_config.c_domain {
+            Some(c_domain) => {
+                let (min, max) = c_domain.bounds();
+                ((min + max) / 2.0, (max - min).norm_sqr().sqrt() / 2.0)
+            }
+            None => (Complex::new(0.0, 0.0), 2.0),
+        };
+        let (result, depth) = self.find_initial_sample_r(&center, radius, 0);
         trace!(depth, "find initial sample recursion completed");
         result
     }
@@ -217,7 +702,7 @@ impl WorkerState {
         }
 
         let view = self.sample_config.view;
-        let mut closest_distance = std::f64::MAX;
+        let mut closest_distance = f64::MAX;
         let mut closest_sample = Complex::new(0.0, 0.0);
 
         for _ in 0..200 {
@@ -227,7 +712,7 @@ impl WorkerState {
             }
 
             // Generate sample for this iteration
-            let sample = seed_r + radius_sample(radius);
+            let sample = seed_r + radius_sample(&mut self.rng, radius);
 
             // If sample doesn't escape than its a dud
             let sample_escapes = self.evaluate(&sample);
@@ -258,22 +743,29 @@ impl WorkerState {
     /// Sampling with the Metropolis-Hastings algorithm is based on mutating a "good" sample
     /// Some of the time we want to perturb the last good sample
     /// Other times we want to try a complelety new point
-    fn mutate(&self, c: &Complex) -> Complex {
+    fn mutate(&mut self, c: &Complex) -> Complex {
         let view = self.sample_config.view;
-        if random_prob() < self.sample_config.random_sample_prob {
-            radius_sample(self.sample_config.norm_cutoff)
-        } else {
-            let mut result = *c;
-            let r1 = 1.0 / view.zoom * 0.0001;
-            let r2 = 1.0 / view.zoom * 0.1;
-            let phi = random_prob() * 2.0 * std::f64::consts::PI;
-            let r = r2 * (-(r2 / r1).ln() * random_prob()).exp();
+        if random_prob(&mut self.rng) < self.sample_config.random_sample_prob {
+            let sample = match &self.sample_config.c_domain {
+                Some(c_domain) => domain_sample(&mut self.rng, c_domain),
+                None => Some(radius_sample(&mut self.rng, self.sample_config.norm_cutoff)),
+            };
+            // If the domain is too hard to hit, perturb the last sample instead
+            if let Some(sample) = sample {
+                return sample;
+            }
+        }
 
-            result.re += r * phi.cos();
-            result.im += r * phi.sin();
+        let mut result = *c;
+        let r1 = 1.0 / view.zoom * self.sample_config.min_mutation_radius;
+        let r2 = 1.0 / view.zoom * self.sample_config.max_mutation_radius;
+        let phi = random_prob(&mut self.rng) * 2.0 * std::f64::consts::PI;
+        let r = r2 * (-(r2 / r1).ln() * random_prob(&mut self.rng)).exp();
 
-            result
-        }
+        result.re += r * phi.cos();
+        result.im += r * phi.sin();
+
+        result
     }
 
     fn transition_probability(&self, orbit_len_1: usize, orbit_len_2: usize) -> f64 {
@@ -286,29 +778,62 @@ impl WorkerState {
         numerator / denominator
     }
 
+    /// Inverse temperature of this worker's chain, 1.0 unless tempering
+    fn beta(&self) -> f64 {
+        self.tempering.as_ref().map_or(1.0, |t| t.beta())
+    }
+
+    /// Probability of moving the chain from z to the mutation,
+    /// with the contributions flattened by the chain's temperature
+    fn acceptance_probability(
+        &self,
+        mutation_contrib: f64,
+        mutation_orbit_len: usize,
+        z_contrib: f64,
+        z_orbit_len: usize,
+    ) -> f64 {
+        let beta = self.beta();
+        let t1 = self.transition_probability(mutation_orbit_len, z_orbit_len);
+        let t2 = self.transition_probability(z_orbit_len, mutation_orbit_len);
+        (((mutation_contrib.powf(beta) * t1).ln() - (z_contrib.powf(beta) * t2).ln()).exp())
+            .clamp(0.0, 1.0)
+    }
+
+    /// Only the cold chain of a tempering ladder records orbits
+    fn records_orbits(&self) -> bool {
+        self.tempering.as_ref().is_none_or(|t| t.is_cold())
+    }
+
     #[tracing::instrument(skip(self))]
-    fn run_metro_instance(&mut self) {
+    fn run_metro_instance(&mut self) -> MetroOutcome {
+        WorkerStats::add(&self.stats.metro_instances, 1);
+        if let Some((trace, _)) = &mut self.diagnostics {
+            trace.clear();
+        }
         // TODO these need to be setup properly
         let mut z = match self.find_initial_sample() {
             Some(z) => z,
             None => {
                 warn!("Failed to find initial sample");
-                return;
+                WorkerStats::add(&self.stats.initial_sample_failures, 1);
+                return MetroOutcome::NoInitialSample;
             }
         };
 
         let mut z_orbit_len = self.orbit_buffer.len();
         let z_orbit_intersections = self.orbit_intersections();
-        let mut z_contrib = self.contribution(z_orbit_intersections);
+        let mut z_contrib = self.contribution(z_orbit_intersections, self.mask_weight());
 
         let mut accepted_samples = 0;
         let mut rejected_samples = 0;
         let mut outside_samples = 0;
 
+        let mut outcome = MetroOutcome::Completed;
         let mut outside_streak = 0;
         for warm_up_sample in 0..self.sample_config.warm_up_samples {
-            if self.stop() {
+            if self.poll_stop() {
                 info!("In warmup stop");
+                outcome = MetroOutcome::Stopped;
                 break;
             }
 
@@ -317,6 +842,7 @@ impl WorkerState {
             // Only orbits that escape should not be counted
             if !self.evaluate(&mutation) {
                 outside_samples += 1;
+                WorkerStats::add(&self.stats.outside, 1);
                 outside_streak += 1;
                 if outside_streak > self.sample_config.outside_limit {
                     warn!(
@@ -326,8 +852,9 @@ impl WorkerState {
                         outside_samples,
                         "Outside streak exceeded in warm up, evaluate"
                     );
-                    return;
+                    return MetroOutcome::WarmUpOutsideStreak;
                 }
+                continue;
             }
 
             let mutation_orbit_len = self.orbit_buffer.len();
@@ -335,6 +862,7 @@ impl WorkerState {
             // If the mutation doesn't intersect at all, it's a dud
             if intersection_count == 0 {
                 outside_samples += 1;
+                WorkerStats::add(&self.stats.outside, 1);
                 outside_streak += 1;
                 if outside_streak > self.sample_config.outside_limit {
                     warn!(
@@ -344,27 +872,31 @@ impl WorkerState {
                         outside_samples,
                         "Outside streak exceeded in warm up"
                     );
-                    return;
+                    return MetroOutcome::WarmUpOutsideStreak;
                 }
                 continue;
             } else {
                 outside_streak = 0;
             }
 
-            let mutation_contrib = self.contribution(intersection_count);
+            let mutation_contrib = self.contribution(intersection_count, self.mask_weight());
 
-            let t1 = self.transition_probability(mutation_orbit_len, z_orbit_len);
-            let t2 = self.transition_probability(z_orbit_len, mutation_orbit_len);
-            let alpha =
-                (((mutation_contrib * t1).ln() - (z_contrib * t2).ln()).exp()).clamp(0.0, 1.0);
+            let alpha = self.acceptance_probability(
+                mutation_contrib,
+                mutation_orbit_len,
+                z_contrib,
+                z_orbit_len,
+            );
 
-            if alpha > random_prob() {
+            if alpha > random_prob(&mut self.rng) {
                 z = mutation;
                 z_contrib = mutation_contrib;
                 z_orbit_len = mutation_orbit_len;
                 accepted_samples += 1;
+                WorkerStats::add(&self.stats.accepted, 1);
             } else {
                 rejected_samples += 1;
+                WorkerStats::add(&self.stats.rejected, 1);
             }
         }
 
@@ -379,14 +911,41 @@ impl WorkerState {
         rejected_samples = 0;
         outside_samples = 0;
         for sample in 0..self.sample_config.samples {
-            if self.stop() {
+            if self.poll_stop() {
                 info!("In sampling stop");
+                outcome = MetroOutcome::Stopped;
                 break;
             }
 
+            if self.samples_spent() {
+                outcome = MetroOutcome::BudgetSpent;
+                break;
+            }
+            self.samples_taken += 1;
+
+            let swap_due = self
+                .tempering
+                .as_ref()
+                .is_some_and(|t| t.swap_due(self.samples_taken));
+            if swap_due {
+                let state = ChainState {
+                    sample: z,
+                    orbit_len: z_orbit_len,
+                    contrib: z_contrib,
+                };
+                let u = random_prob(&mut self.rng);
+                let tempering = self.tempering.as_mut().unwrap();
+                if let Some(swapped) = tempering.exchange(state, u) {
+                    z = swapped.sample;
+                    z_contrib = swapped.contrib;
+                    z_orbit_len = swapped.orbit_len;
+                }
+            }
+
             let mutation = self.mutate(&z);
             if !self.evaluate(&mutation) {
                 outside_samples += 1;
+                WorkerStats::add(&self.stats.outside, 1);
                 outside_streak += 1;
                 if outside_streak > self.sample_config.outside_limit {
                     warn!(
@@ -396,17 +955,23 @@ impl WorkerState {
                         outside_samples,
                         "Outside streak exceeded, evaluate"
                     );
-                    return;
+                    return MetroOutcome::OutsideStreak;
                 }
+                self.trace_step(z_contrib, false);
                 continue;
             }
 
             let mutation_orbit_len = self.orbit_buffer.len();
-            let intersection_count = self.record_orbit();
+            let intersection_count = if self.records_orbits() {
+                self.record_orbit()
+            } else {
+                self.orbit_intersections()
+            };
 
             // If the mutation doesn't intersect at all, it's a dud
             if intersection_count == 0 {
                 outside_samples += 1;
+                WorkerStats::add(&self.stats.outside, 1);
                 outside_streak += 1;
 
                 if outside_streak > self.sample_config.outside_limit {
@@ -417,27 +982,37 @@ impl WorkerState {
                         outside_samples,
                         "Outside streak exceeded in sampling"
                     );
-                    return;
+                    return MetroOutcome::OutsideStreak;
                 }
+                self.trace_step(z_contrib, false);
                 continue;
             } else {
                 outside_streak = 0;
             }
 
-            let mutation_contrib = self.contribution(intersection_count);
+            let mutation_contrib = self.contribution(intersection_count, self.mask_weight());
 
-            let t1 = self.transition_probability(mutation_orbit_len, z_orbit_len);
-            let t2 = self.transition_probability(z_orbit_len, mutation_orbit_len);
-            let alpha =
-                (((mutation_contrib * t1).ln() - (z_contrib * t2).ln()).exp()).clamp(0.0, 1.0);
+            let alpha = self.acceptance_probability(
+                mutation_contrib,
+                mutation_orbit_len,
+                z_contrib,
+                z_orbit_len,
+            );
 
-            if alpha > random_prob() {
+            if alpha > random_prob(&mut self.rng) {
                 z = mutation;
                 z_contrib = mutation_contrib;
                 z_orbit_len = mutation_orbit_len;
                 accepted_samples += 1;
+                WorkerStats::add(&self.stats.accepted, 1);
+                self.trace_step(z_contrib, true);
+                if self.records_orbits() {
+                    self.record_c_density(&z, intersection_count);
+                }
             } else {
                 rejected_samples += 1;
+                WorkerStats::add(&self.stats.rejected, 1);
+                self.trace_step(z_contrib, false);
             }
         }
 
@@ -445,27 +1020,103 @@ impl WorkerState {
             accepted_samples,
             rejected_samples, outside_samples, "Sampling complete"
         );
+        outcome
     }
 
     fn stop(&self) -> bool {
         self.stop_switch.read().stop()
     }
 
+    /// Answer any pending snapshot request, then check the stop switch
+    fn poll_stop(&mut self) -> bool {
+        if let Some(requests) = &self.snapshots {
+            let epoch = requests.epoch.load(Ordering::Relaxed);
+            if epoch != self.snapshot_epoch {
+                self.snapshot_epoch = epoch;
+                let grids = match &self.grids {
+                    WorkerGrids::Local(grids) if self.records_orbits() => grids.clone(),
+                    _ => Vec::new(),
+                };
+                // The sampler may have given up waiting, which is fine
+                let _ = requests.sender.send((epoch, grids));
+            }
+        }
+        self.stop()
+    }
+
     #[tracing::instrument(skip(self))]
     fn run_worker(mut self) -> Self {
         let mut metro_instances = 0;
-        while !self.stop() {
+        let mut failed_streak = 0;
+        while !self.poll_stop() {
+            if self.budget_spent(metro_instances) {
+                info!(self.samples_taken, "Worker budget spent");
+                self.finish_budget();
+                break;
+            }
+
+            // Failed instances may take no samples, which would never spend a sample budget
+            if self.budget.is_limited() && failed_streak >= MAX_FAILED_INSTANCES {
+                warn!(
+                    self.samples_taken,
+                    failed_streak, "Metro instances keep failing, giving up on the worker budget"
+                );
+                self.finish_budget();
+                break;
+            }
+
             metro_instances += 1;
             trace!(metro_instances, "Starting metro instance");
-            self.run_metro_instance();
+            let samples_before = self.samples_taken;
+            let outcome = self.run_metro_instance();
+            if outcome.is_failure() {
+                WorkerStats::add(&self.stats.metro_failures, 1);
+            }
+            if outcome.is_failure() && self.samples_taken == samples_before {
+                failed_streak += 1;
+            } else {
+                failed_streak = 0;
+            }
+            if let Some((trace, diagnostics)) = &self.diagnostics {
+                diagnostics.lock().record_chain(trace, outcome);
+            }
         }
         info!("Ran {} metro instances", metro_instances);
+        if let Some(tempering) = &self.tempering {
+            tempering.retire();
+            info!(tempering.swaps, "Tempering swaps accepted");
+        }
         self
     }
+
+    fn finish_budget(&self) {
+        if let Some(budget_workers) = &self.budget_workers {
+            if budget_workers.fetch_sub(1, Ordering::Relaxed) == 1 {
+                let mut switch = self.stop_switch.write();
+                if !switch.stop() {
+                    switch.trigger("Sampling budget spent");
+                }
+            }
+        }
+    }
 }
 
+/// Merging only tiled grids produces a tiled grid, otherwise the result is dense
 #[tracing::instrument(skip(config, grids))]
-fn merge_grids(config: &SampleConfig, grids: Vec<&CountGrid>) -> CountGrid {
+fn merge_grids(config: &SampleConfig, grids: Vec<&HistogramGrid>) -> HistogramGrid {
+    if grids
+        .iter()
+        .all(|grid| matches!(grid, HistogramGrid::Tiled(_)))
+    {
+        let mut result = TiledCountGrid::zero(config.view.width, config.view.height);
+        for grid in grids {
+            if let HistogramGrid::Tiled(tiled) = grid {
+                result.add(tiled);
+            }
+        }
+        return HistogramGrid::Tiled(result);
+    }
+
     let mut result = CountGrid::zero(config.view.width, config.view.height);
     for x in 0..config.view.width {
         for y in 0..config.view.height {
@@ -477,19 +1128,19 @@ fn merge_grids(config: &SampleConfig, grids: Vec<&CountGrid>) -> CountGrid {
         }
     }
 
-    result
+    HistogramGrid::Dense(result)
 }
 
 //#[tracing::instrument(skip(config, results))]
 async fn merge_results<
     E: 'static + Send + Sync,
-    F: 'static + Fn(&E, usize) -> &CountGrid + Send + Sync + Copy,
+    F: 'static + Fn(&E, usize) -> &HistogramGrid + Send + Sync + Copy,
 >(
     config: Arc<SampleConfig>,
     results: Arc<Vec<E>>,
     extract: F,
-) -> Result<Vec<CountGrid>, EscapeError> {
-    let cutoff_count = config.cutoffs.len();
+) -> Result<Vec<HistogramGrid>, EscapeError> {
+    let cutoff_count = config.layer_count();
     let mut tasks = Vec::with_capacity(cutoff_count);
     for cutoff_index in 0..cutoff_count {
         let c = config.clone();
@@ -511,6 +1162,251 @@ async fn merge_results<
     Ok(result)
 }
 
+/// A set of sampling workers running in the background
+pub struct Sampler {
+    config: Arc<SampleConfig>,
+    shared_grids: Option<Arc<Vec<AtomicGrid>>>,
+    workers: Vec<tokio::task::JoinHandle<WorkerState>>,
+    stats: Vec<ArcStats>,
+    stop_switch: ArcSwitch,
+    snapshots: Arc<SnapshotRequests>,
+    snapshot_receiver: tokio::sync::Mutex<mpsc::UnboundedReceiver<(u64, Vec<HistogramGrid>)>>,
+    diagnostics: Option<Arc<Mutex<Diagnostics>>>,
+}
+
+impl Sampler {
+    /// Validate the config and start the workers, which run until the stop switch is triggered
+    /// If a seed is given, worker i is seeded with seed + i
+    /// With a limited budget, the stop switch is triggered once every worker has spent its share
+    pub fn start(
+        config: Arc<SampleConfig>,
+        workers: usize,
+        strategy: GridStrategy,
+        seed: Option<u64>,
+        budget: WorkBudget,
+        collect_diagnostics: bool,
+        stop_switch: ArcSwitch,
+    ) -> Result<Sampler, EscapeError> {
+        config.validate()?;
+
+        let mask = match &config.mask {
+            Some(mask_config) => {
+                let mask = Mask::load(mask_config, &config.view)?;
+                info!("Loaded mask {}", mask_config.path.display());
+                Some(Arc::new(mask))
+            }
+            None => None,
+        };
+
+        // Each tempering ladder shares an exchange between its workers
+        let mut exchanges = Vec::new();
+        if let Some(tempering) = &config.tempering {
+            tempering.validate(workers)?;
+            for _ in 0..workers / tempering.ladder_size() {
+                exchanges.push(Exchange::new(tempering));
+            }
+            info!(ladders = exchanges.len(), "Parallel tempering enabled");
+        }
+
+        let shared_grids = if strategy == GridStrategy::Shared {
+            info!("Workers will record to shared grids");
+            Some(WorkerGrids::shared(&config))
+        } else {
+            None
+        };
+
+        let (sender, snapshot_receiver) = mpsc::unbounded_channel();
+        let snapshots = Arc::new(SnapshotRequests {
+            epoch: AtomicU64::new(0),
+            sender,
+        });
+
+        let budget_workers = Arc::new(AtomicUsize::new(workers));
+        let diagnostics = collect_diagnostics.then(|| Arc::new(Mutex::new(Diagnostics::default())));
+        let mut handles = Vec::with_capacity(workers);
+        let mut stats = Vec::with_capacity(workers);
+        for worker in 0..workers {
+            let s = stop_switch.clone();
+            let c = config.clone();
+            let grids = match &shared_grids {
+                Some(shared) => WorkerGrids::Shared(shared.clone()),
+                None => WorkerGrids::local(&c, strategy == GridStrategy::Tiled),
+            };
+            let tempering = c.tempering.as_ref().map(|t| {
+                let ladder = worker / t.ladder_size();
+                let rung = worker % t.ladder_size();
+                TemperingRung::new(t, exchanges[ladder].clone(), rung)
+            });
+            let worker_seed = seed.map(|seed| seed.wrapping_add(worker as u64));
+            let worker_stats = WorkerStats::new(c.layer_count(), c.max_iteration());
+            stats.push(worker_stats.clone());
+            let worker_snapshots = shared_grids.is_none().then(|| snapshots.clone());
+            let worker_budget = budget.share(worker, workers);
+            let b = budget_workers.clone();
+            let worker_diagnostics = diagnostics.clone();
+            let worker_mask = mask.clone();
+            handles.push(tokio::task::spawn_blocking(move || {
+                let mut state = WorkerState::new(
+                    &c,
+                    grids,
+                    s,
+                    tempering,
+                    worker_seed,
+                    worker_stats,
+                    worker_snapshots,
+                );
+                if budget.is_limited() {
+                    state = state.with_budget(worker_budget, b);
+                }
+                if let Some(diagnostics) = worker_diagnostics {
+                    state = state.with_diagnostics(diagnostics);
+                }
+                if let Some(mask) = worker_mask {
+                    state = state.with_mask(mask);
+                }
+                state.run_worker()
+            }));
+            trace!(worker, "Created worker future");
+        }
+        info!(workers, "Started sampling workers");
+
+        Ok(Sampler {
+            config,
+            shared_grids,
+            workers: handles,
+            stats,
+            stop_switch,
+            snapshots,
+            snapshot_receiver: tokio::sync::Mutex::new(snapshot_receiver),
+            diagnostics,
+        })
+    }
+
+    /// Chain diagnostics shared by the workers, if they were asked to collect them
+    pub fn diagnostics(&self) -> Option<Arc<Mutex<Diagnostics>>> {
+        self.diagnostics.clone()
+    }
+
+    /// Copies of the grids each recording worker has so far, without stopping the workers
+    /// Returns None for shared grids, or if sampling stops before every worker has answered
+    pub async fn worker_snapshots(&self) -> Option<Vec<Vec<HistogramGrid>>> {
+        if self.shared_grids.is_some() {
+            return None;
+        }
+
+        let mut receiver = self.snapshot_receiver.lock().await;
+        let epoch = self.snapshots.epoch.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut answers = 0;
+        let mut results = Vec::with_capacity(self.workers.len());
+        while answers < self.workers.len() {
+            match tokio::time::timeout(Duration::from_millis(250), receiver.recv()).await {
+                // Answers to abandoned requests are ignored
+                Ok(Some((answer_epoch, grids))) => {
+                    if answer_epoch == epoch {
+                        answers += 1;
+                        if !grids.is_empty() {
+                            results.push(grids);
+                        }
+                    }
+                }
+                Ok(None) => return None,
+                Err(_) => {
+                    if self.stop_switch.read().stop() {
+                        return None;
+                    }
+                }
+            }
+        }
+
+        Some(results)
+    }
+
+    /// Merge copies of the grids recorded so far, without stopping the workers
+    /// Returns None if sampling stops before every worker has answered
+    pub async fn snapshot(&self) -> Result<Option<Vec<HistogramGrid>>, EscapeError> {
+        if let Some(shared) = &self.shared_grids {
+            return Ok(Some(
+                shared
+                    .iter()
+                    .map(|grid| HistogramGrid::Dense(grid.to_grid()))
+                    .collect(),
+            ));
+        }
+
+        let results = match self.worker_snapshots().await {
+            Some(results) => results,
+            None => return Ok(None),
+        };
+        let merged = merge_results(self.config.clone(), Arc::new(results), |x, i| &x[i]).await?;
+        Ok(Some(merged))
+    }
+
+    /// The config the workers were started with
+    pub fn config(&self) -> &SampleConfig {
+        &self.config
+    }
+
+    /// The counters of each worker, which stay readable while they run
+    pub fn stats(&self) -> Vec<ArcStats> {
+        self.stats.clone()
+    }
+
+    /// Take the counts recorded so far out of shared grids, leaving them empty
+    /// Returns None if the workers are not using shared grids
+    pub fn drain(&self) -> Option<Vec<HistogramGrid>> {
+        self.shared_grids.as_ref().map(|shared| {
+            shared
+                .iter()
+                .map(|grid| HistogramGrid::Tiled(grid.drain()))
+                .collect()
+        })
+    }
+
+    /// Wait for the workers to stop, then merge their results
+    pub async fn finish(self) -> Result<Vec<HistogramGrid>, EscapeError> {
+        Ok(self.finish_with_channels().await?.0)
+    }
+
+    /// Wait for the workers, merging their grids and summing their channels
+    pub async fn finish_with_channels(self) -> Result<(Vec<HistogramGrid>, Channels), EscapeError> {
+        let mut results = Vec::with_capacity(self.workers.len());
+        let mut channels = Channels::new();
+        for w in self.workers {
+            let state = w.await?;
+            channels::add_channels(&mut channels, &state.channels.into_channels());
+            if let WorkerGrids::Local(grids) = state.grids {
+                results.push(grids);
+            }
+        }
+        info!("Sampling workers have completed");
+
+        let merged_grids = match self.shared_grids {
+            Some(shared) => shared
+                .iter()
+                .map(|grid| HistogramGrid::Dense(grid.to_grid()))
+                .collect(),
+            None => {
+                let arc_results = Arc::new(results);
+                let merged = merge_results(self.config, arc_results, |x, i| &x[i]).await?;
+                info!("Worker results have been merged");
+                merged
+            }
+        };
+
+        Ok((merged_grids, channels))
+    }
+}
+
+/// Wait for a task that ran alongside sampling
+/// Its failure is only logged, so that it can't cost the sampled result
+async fn join_auxiliary(name: &str, task: tokio::task::JoinHandle<EscapeResult>) {
+    match task.await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => warn!("{} failed: {:?}", name, e),
+        Err(e) => warn!("{} task failed: {:?}", name, e),
+    }
+}
+
 async fn async_sampling(cli_options: &SampleOptions) -> EscapeResult {
     let logger_builder = tracing_subscriber::fmt()
         .with_timer(tracing_subscriber::fmt::time::uptime())
@@ -524,38 +1420,183 @@ async fn async_sampling(cli_options: &SampleOptions) -> EscapeResult {
     }
 
     let mut config_reader = BufReader::new(std::fs::File::open(&cli_options.config)?);
-    let config: Arc<SampleConfig> = Arc::new(serde_json::from_reader(&mut config_reader)?);
+    let mut config: SampleConfig = serde_json::from_reader(&mut config_reader)?;
+    mask::pin_hash(&mut config)?;
+    let config = Arc::new(config);
     trace!("Sample config loaded: {}", &cli_options.config.display());
 
-    let stop_switch = StopSwitch::new(&cli_options.duration).await;
-    let mut futures = Vec::with_capacity(cli_options.workers);
-    for worker in 0..cli_options.workers {
-        let s = stop_switch.clone();
-        let c = config.clone();
-        futures.push(tokio::spawn(async move {
-            let state = WorkerState::new(&c, s);
-            state.run_worker()
-        }));
-        trace!(worker, "Created worker future");
+    let preview_config = match &cli_options.preview_draw_config {
+        Some(path) => {
+            let mut reader = BufReader::new(std::fs::File::open(path)?);
+            let draw_config: DrawConfig = serde_json::from_reader(&mut reader)?;
+            Some(draw_config)
+        }
+        None => None,
+    };
+    let snapshot_outputs = SnapshotOutputs::new(
+        &cli_options.output,
+        cli_options.snapshot_output.as_deref(),
+        preview_config,
+    );
+
+    let mut strategy = if cli_options.shared_grids {
+        GridStrategy::Shared
+    } else if cli_options.tiled_grids {
+        GridStrategy::Tiled
+    } else {
+        GridStrategy::Local
+    };
+    let estimate = MemoryEstimate::new(&config, cli_options.workers, strategy);
+    info!(
+        "Estimated peak memory {} ({} grids, {} channels, {} orbit buffers, {} merge, {} snapshots)",
+        format_bytes(estimate.peak()),
+        format_bytes(estimate.sampling_grids),
+        format_bytes(estimate.channel_grids),
+        format_bytes(estimate.orbit_buffers),
+        format_bytes(estimate.merge_grids),
+        format_bytes(estimate.snapshot_grids),
+    );
+    if let Some(MemorySize(budget)) = cli_options.max_memory {
+        strategy = memory::fit_budget(&config, cli_options.workers, strategy, budget)?.0;
     }
-    info!(cli_options.workers, "Started sampling workers");
 
-    let mut results = Vec::with_capacity(cli_options.workers);
-    for w in futures {
-        results.push(w.await?.grids);
+    if config.convergence.is_some() {
+        let ladder_size = config.tempering.as_ref().map_or(1, |t| t.ladder_size());
+        convergence::check_workers(
+            cli_options.workers / ladder_size,
+            strategy == GridStrategy::Shared,
+        )?;
     }
-    let arc_results = Arc::new(results);
-    info!("Sampling workers have completed");
 
-    let merged_grids = merge_results(config.clone(), arc_results, |x, i| &x[i]).await?;
-    info!("Worker results have been merged");
+    // Open outputs before sampling starts, so a bad path doesn't waste a run
+    let progress_file = match &cli_options.progress_file {
+        Some(path) => Some(std::fs::File::create(path)?),
+        None => None,
+    };
+    let metrics_listener = match &cli_options.metrics_addr {
+        Some(address) => Some(TcpListener::bind(address).await?),
+        None => None,
+    };
+    let signal = user_signal()?;
+    let timelapse_writer = match &cli_options.timelapse {
+        Some(path) => Some(TimelapseWriter::create(path, &config)?),
+        None => None,
+    };
 
-    HistogramResult::save(&config, &merged_grids, &cli_options.output)?;
+    let stop_switch = StopSwitch::new(&cli_options.duration).await;
+    let sampler = Sampler::start(
+        config.clone(),
+        cli_options.workers,
+        strategy,
+        cli_options.seed,
+        WorkBudget {
+            samples: cli_options.max_samples,
+            metro_instances: cli_options.max_metro_instances,
+        },
+        cli_options.diagnostics,
+        stop_switch.clone(),
+    )?;
+
+    let progress = if cli_options.progress_interval > 0 {
+        Some(tokio::spawn(report_progress(
+            sampler.stats(),
+            cli_options.progress_interval,
+            cli_options.duration,
+            progress_file,
+            stop_switch.clone(),
+        )))
+    } else {
+        None
+    };
+
+    let metrics = metrics_listener.map(|listener| {
+        tokio::spawn(serve_metrics(
+            listener,
+            sampler.stats(),
+            (*config).clone(),
+            stop_switch.clone(),
+        ))
+    });
+
+    let convergence = async {
+        if let Some(convergence) = &config.convergence {
+            stop_on_convergence(&sampler, convergence, &stop_switch).await;
+        }
+    };
+    let snapshots = snapshot_on_demand(
+        &sampler,
+        &config,
+        signal,
+        cli_options.snapshot_trigger.as_deref(),
+        &snapshot_outputs,
+        &stop_switch,
+    );
+    let timelapse_start = Instant::now();
+    let timelapse = async {
+        match timelapse_writer {
+            Some(writer) => {
+                record_timelapse(
+                    &sampler,
+                    writer,
+                    timelapse_start,
+                    cli_options.timelapse_schedule,
+                    &stop_switch,
+                )
+                .await
+            }
+            None => None,
+        }
+    };
+    let (_, _, timelapse_writer) = tokio::join!(convergence, snapshots, timelapse);
+
+    let diagnostics = sampler.diagnostics();
+    let stats = sampler.stats();
+    let (merged_grids, channels) = sampler.finish_with_channels().await?;
+    // The last frame is the finished histogram, so the frames add up to the result
+    if let Some(mut writer) = timelapse_writer {
+        let elapsed = timelapse_start.elapsed().as_secs_f64();
+        match writer.write_frame(elapsed, &merged_grids) {
+            Ok(()) => info!(elapsed, "Last time-lapse frame written"),
+            Err(e) => warn!("Failed to write the last time-lapse frame: {:?}", e),
+        }
+    }
+    if let Some(progress) = progress {
+        join_auxiliary("Progress reporting", progress).await;
+    }
+    if let Some(metrics) = metrics {
+        join_auxiliary("Metrics server", metrics).await;
+    }
+
+    let spectrum = StatsSnapshot::total(&stats).orbit_spectrum();
+    HistogramResult::save_with(
+        &config,
+        &merged_grids,
+        Some(&spectrum),
+        &channels,
+        &cli_options.output,
+    )?;
     info!(
         "Result has been written to {}",
         &cli_options.output.display()
     );
 
+    if let Some(diagnostics) = diagnostics {
+        let report = diagnostics.lock().report();
+        info!(
+            chains = report.chains,
+            acceptance_rate = report.acceptance_rate,
+            effective_sample_size = report.effective_sample_size,
+            "Chain diagnostics"
+        );
+        let stem = cli_options.output.file_stem().unwrap_or_default();
+        let path = cli_options
+            .output
+            .with_file_name(format!("{}_diagnostics.json", stem.to_string_lossy()));
+        let writer = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        serde_json::to_writer_pretty(writer, &report)?;
+        info!("Diagnostics have been written to {}", path.display());
+    }
+
     Ok(())
 }
 
@@ -593,17 +1634,30 @@ async fn async_merge(cli_options: &MergeOptions) -> EscapeResult {
     // Open all the files / HistogramResults
     let mut result_futures = Vec::with_capacity(histogram_count);
     for path in cli_options.histograms.clone() {
-        result_futures.push(tokio::spawn(
-            async move { HistogramResult::from_file(&path) },
-        ));
+        result_futures.push(tokio::spawn(async move { HistogramResult::load(&path) }));
     }
 
     let mut configs = Vec::with_capacity(histogram_count);
     let mut results = Vec::with_capacity(histogram_count);
-    for r in result_futures {
+    let mut spectrum: Option<OrbitSpectrum> = None;
+    let mut channels = Vec::with_capacity(histogram_count);
+    for (i, r) in result_futures.into_iter().enumerate() {
         let histogram = r.await??;
-        configs.push(histogram.0);
-        results.push(histogram.1)
+        // The merged spectrum is only meaningful if every input recorded one, with the same bins
+        spectrum = match (spectrum, histogram.spectrum) {
+            (None, Some(first)) if i == 0 => Some(first),
+            (Some(mut total), Some(other)) => {
+                if total.bins_per_octave != other.bins_per_octave {
+                    return Err(EscapeError::IncompatibleHistograms);
+                }
+                total.add(&other);
+                Some(total)
+            }
+            _ => None,
+        };
+        configs.push(Arc::new(histogram.config));
+        results.push(histogram.grids);
+        channels.push(histogram.channels);
     }
     let arc_results = Arc::new(results);
     info!("Files loaded");
@@ -611,7 +1665,7 @@ async fn async_merge(cli_options: &MergeOptions) -> EscapeResult {
     // Check compatability
     let mut check = true;
     for i in 1..configs.len() {
-        check |= configs[i - 1].compatible(&configs[i]);
+        check &= configs[i - 1].compatible(&configs[i]);
     }
     if !check {
         return Err(EscapeError::IncompatibleHistograms);
@@ -621,7 +1675,14 @@ async fn async_merge(cli_options: &MergeOptions) -> EscapeResult {
     let result = merge_results(configs[0].clone(), arc_results, |x, i| &x[i]).await?;
     info!("Results have been merged");
 
-    HistogramResult::save(&configs[0], &result, &cli_options.output)?;
+    let channels = channels::merge_channels(&channels);
+    HistogramResult::save_with(
+        &configs[0],
+        &result,
+        spectrum.as_ref(),
+        &channels,
+        &cli_options.output,
+    )?;
     info!("Result saved to {}", cli_options.output.display());
 
     Ok(())
@@ -640,10 +1701,16 @@ pub fn run_merge(merge_options: &MergeOptions) -> EscapeResult {
 }
 
 pub fn run_report(report_options: &ReportOptions) -> EscapeResult {
-    let (_, histogram) = HistogramResult::from_file(&report_options.histogram)?;
-    for (i, grid) in histogram.iter().enumerate() {
+    let result = HistogramResult::load(&report_options.histogram)?;
+    let histogram = result.grids;
+    for (i, histogram_grid) in histogram.iter().enumerate() {
+        let grid = histogram_grid.to_dense();
         let data: Vec<f64> = grid.data().iter().map(|c| *c as f64).collect();
         println!("Grid {}:", i);
+        if let HistogramGrid::Tiled(tiled) = histogram_grid {
+            let (allocated, total) = tiled.tile_usage();
+            println!("  tiles: {} / {}", allocated, total);
+        }
         let min = grid.data().iter().min().unwrap();
         println!("  min: {}", min);
         let max = grid.data().iter().max().unwrap();
@@ -656,6 +1723,17 @@ pub fn run_report(report_options: &ReportOptions) -> EscapeResult {
         println!("  stdev: {}", sd);
         println!();
     }
+
+    if let Some(spectrum) = result.spectrum {
+        println!("Orbit spectrum:");
+        println!("  hits: {}", spectrum.total());
+        for q in [0.1, 0.25, 0.5, 0.75, 0.9, 0.99] {
+            if let Some(length) = spectrum.quantile(q) {
+                println!("  {:.0}% of hits from orbits <= {}", q * 100.0, length);
+            }
+        }
+        println!();
+    }
     Ok(())
 }
 
@@ -666,7 +1744,7 @@ mod tests {
     #[test]
     fn radius_sampling() {
         for _ in 0..500 {
-            let c = radius_sample(2.0);
+            let c = radius_sample(&mut rand::thread_rng(), 2.0);
             assert!(c.re >= -2.0);
             assert!(c.re <= 2.0);
             assert!(c.im >= -2.0);
@@ -675,15 +1753,648 @@ mod tests {
         }
     }
 
+    #[test]
+    fn domain_sampling() {
+        let domains: Vec<CDomain> = serde_json::from_str(
+            r#"[
+                { "type": "rectangle", "min": [-1.0, 0.0], "max": [0.0, 0.5] },
+                { "type": "annulus", "center": [0.0, 0.0], "inner_radius": 0.5, "outer_radius": 1.0 },
+                { "type": "polygon", "points": [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]] },
+                { "type": "disks", "disks": [
+                    { "center": [-0.1226, 0.7449], "radius": 0.095 },
+                    { "center": [-1.0, 0.0], "radius": 0.25 }
+                ] }
+            ]"#,
+        )
+        .unwrap();
+        for domain in &domains {
+            assert!(domain.validate().is_ok());
+            for _ in 0..500 {
+                let c = domain_sample(&mut rand::thread_rng(), domain).unwrap();
+                assert!(domain.contains(&c));
+            }
+        }
+        assert!(!domains[1].contains(&Complex::new(0.1, 0.1)));
+        assert!(domains[2].contains(&Complex::new(0.2, 0.2)));
+        assert!(!domains[2].contains(&Complex::new(0.6, 0.6)));
+        assert!(domains[3].contains(&Complex::new(-1.1, 0.1)));
+
+        // Only the annulus is closed under conjugation, the first disk has no mirror
+        let symmetric: Vec<bool> = domains.iter().map(|d| d.is_conjugate_symmetric()).collect();
+        assert_eq!(symmetric, vec![false, true, false, false]);
+        let diamond: CDomain = serde_json::from_str(
+            r#"{ "type": "polygon", "points": [[-1.0, 0.0], [0.0, -1.0], [1.0, 0.0], [0.0, 1.0]] }"#,
+        )
+        .unwrap();
+        assert!(diamond.is_conjugate_symmetric());
+        let config: SampleConfig = serde_json::from_str(
+            r#"{
+                "cutoffs": [50],
+                "view": { "width": 100, "height": 100, "zoom": 0.25, "center": [0.0, 0.0] },
+                "symmetry": true,
+                "c_domain": { "type": "rectangle", "min": [-1.0, 0.0], "max": [0.0, 0.5] }
+            }"#,
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+
+        // Flat polygons have no area, and tiny disks far apart are too hard to hit
+        let flat: CDomain = serde_json::from_str(
+            r#"{ "type": "polygon", "points": [[0.0, 0.0], [1.0, 1.0], [2.0, 2.0]] }"#,
+        )
+        .unwrap();
+        assert!(flat.validate().is_err());
+        let sparse: CDomain = serde_json::from_str(
+            r#"{ "type": "disks", "disks": [
+                { "center": [-1.5, -1.5], "radius": 1e-6 },
+                { "center": [1.5, 1.5], "radius": 1e-6 }
+            ] }"#,
+        )
+        .unwrap();
+        assert!(sparse.validate().is_ok());
+        assert!(domain_sample(&mut rand::thread_rng(), &sparse).is_none());
+    }
+
     #[test]
     fn prob_sampling() {
         for _ in 0..500 {
-            let p = random_prob();
+            let p = random_prob(&mut rand::thread_rng());
             assert!(p >= 0.0);
             assert!(p <= 1.0);
         }
     }
 
+    #[test]
+    fn symmetry_folds_onto_mirrored_row() {
+        let mut config: SampleConfig = serde_json::from_str(
+            r#"{
+                "cutoffs": [50],
+                "view": { "width": 100, "height": 100, "zoom": 0.25, "center": [0.0, 0.0] },
+                "symmetry": true
+            }"#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+
+        let switch = StopSwitch::without_handlers();
+        let shared = WorkerGrids::shared(&config);
+        let grids = WorkerGrids::Shared(shared.clone());
+        let mut state = WorkerState::new(
+            &config,
+            grids,
+            switch,
+            None,
+            None,
+            WorkerStats::new(config.layer_count(), config.max_iteration()),
+            None,
+        );
+        state.orbit_buffer.push(Complex::new(0.5, 1.0));
+        assert_eq!(state.record_orbit(), 1);
+        let (x, y) = state.project(&Complex::new(0.5, 1.0)).unwrap();
+        let grid = shared[0].to_grid();
+        assert_eq!(grid.value(x, y), 1);
+        assert_eq!(grid.value(x, 99 - y), 1);
+
+        config.julia_set_param = Complex::new(0.0, 0.1);
+        assert!(config.validate().is_err());
+
+        // Conjugates of orbits through an off axis view mostly miss it, so they can't be recorded
+        config.julia_set_param = Complex::new(0.0, 0.0);
+        config.view.center = Complex::new(0.0, 0.5);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn only_cold_chains_record() {
+        let config: SampleConfig = serde_json::from_str(
+            r#"{
+                "cutoffs": [50],
+                "view": { "width": 40, "height": 40, "zoom": 0.25, "center": [0.0, 0.0] },
+                "samples": 500,
+                "warm_up_samples": 10,
+                "tempering": { "temperatures": [1.0, 2.0], "swap_interval": 100000 }
+            }"#,
+        )
+        .unwrap();
+        let tempering = config.tempering.as_ref().unwrap();
+        let exchange = Exchange::new(tempering);
+
+        let recorded = |rung: usize| {
+            let stats = WorkerStats::new(config.layer_count(), config.max_iteration());
+            let mut state = WorkerState::new(
+                &config,
+                WorkerGrids::local(&config, false),
+                StopSwitch::without_handlers(),
+                Some(TemperingRung::new(tempering, exchange.clone(), rung)),
+                Some(5),
+                stats.clone(),
+                None,
+            );
+            while stats.snapshot().accepted == 0 {
+                state.run_metro_instance();
+            }
+            match &state.grids {
+                WorkerGrids::Local(grids) => grids[0].to_dense().data().iter().sum::<u64>(),
+                WorkerGrids::Shared(_) => unreachable!(),
+            }
+        };
+        assert!(recorded(0) > 0);
+        assert_eq!(recorded(1), 0);
+    }
+
+    #[test]
+    fn layer_ranges_select_layers() {
+        let mut config: SampleConfig = serde_json::from_str(
+            r#"{
+                "layers": [[1, 50], [20, 500], [1000, 5000]],
+                "view": { "width": 100, "height": 100, "zoom": 0.25, "center": [0.0, 0.0] }
+            }"#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.layer_count(), 3);
+        assert_eq!(config.max_iteration(), 5000);
+
+        let record = |config: &SampleConfig, len: usize| {
+            let shared = WorkerGrids::shared(config);
+            let mut state = WorkerState::new(
+                config,
+                WorkerGrids::Shared(shared.clone()),
+                StopSwitch::without_handlers(),
+                None,
+                None,
+                WorkerStats::new(config.layer_count(), config.max_iteration()),
+                None,
+            );
+            state.orbit_buffer = vec![Complex::new(0.5, 1.0); len];
+            let hits = state.record_orbit();
+            let layers: Vec<u64> = shared
+                .iter()
+                .map(|g| g.to_grid().data().iter().sum())
+                .collect();
+            (hits, layers)
+        };
+        assert_eq!(record(&config, 30), (30, vec![30, 0, 0]));
+        assert_eq!(record(&config, 700), (0, vec![0, 0, 0]));
+        let exclusive = config.clone();
+        let mut covered = config.clone();
+        covered.layers = Some(vec![[1, 50], [20, 40], [1000, 5000]]);
+        assert_eq!(covered.reachable_layers(), vec![true, false, true]);
+        config.cumulative_layers = true;
+        assert_eq!(record(&config, 30), (30, vec![30, 30, 0]));
+        assert!(!config.compatible(&exclusive));
+        let mut shifted = config.clone();
+        shifted.layers = Some(vec![[1, 50], [20, 500], [1000, 6000]]);
+        assert!(!config.compatible(&shifted));
+        covered.cumulative_layers = true;
+        assert_eq!(covered.reachable_layers(), vec![true, true, true]);
+
+        config.cutoffs = vec![10];
+        assert!(config.validate().is_err());
+        config.layers = None;
+        assert_eq!(config.layer_ranges(), vec![0..=10]);
+    }
+
+    #[test]
+    fn orbit_window_limits_recording() {
+        let config: SampleConfig = serde_json::from_str(
+            r#"{
+                "cutoffs": [100],
+                "view": { "width": 100, "height": 100, "zoom": 0.25, "center": [0.0, 0.0] },
+                "skip_iterations": 10,
+                "last_iterations": 30,
+                "iteration_stride": 4
+            }"#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        let mut strided = config.clone();
+        strided.iteration_stride = 2;
+        assert!(!config.compatible(&strided));
+        let mut short = config.clone();
+        short.cutoffs = vec![10, 100];
+        assert_eq!(short.reachable_layers(), vec![false, true]);
+
+        let mut state = WorkerState::new(
+            &config,
+            WorkerGrids::local(&config, false),
+            StopSwitch::without_handlers(),
+            None,
+            None,
+            WorkerStats::new(config.layer_count(), config.max_iteration()),
+            None,
+        );
+        // The last 30 of 50 points, starting at 20, every 4th
+        state.orbit_buffer = vec![Complex::new(0.5, 1.0); 50];
+        assert_eq!(state.orbit_window(), Some((20, 4)));
+        assert_eq!(state.orbit_intersections(), 8);
+        assert_eq!(state.record_orbit(), 8);
+
+        // Short orbits are skipped entirely
+        state.orbit_buffer = vec![Complex::new(0.5, 1.0); 5];
+        assert_eq!(state.record_orbit(), 0);
+    }
+
+    #[test]
+    fn weights_are_recorded_per_layer() {
+        let config: SampleConfig = serde_json::from_str(
+            r#"{
+                "cutoffs": [10, 100],
+                "view": { "width": 100, "height": 100, "zoom": 0.25, "center": [0.0, 0.0] },
+                "weighting": { "type": "iteration_index" }
+            }"#,
+        )
+        .unwrap();
+        let mut unweighted = config.clone();
+        unweighted.weighting = None;
+        assert!(!config.compatible(&unweighted));
+
+        let mut state = WorkerState::new(
+            &config,
+            WorkerGrids::local(&config, false),
+            StopSwitch::without_handlers(),
+            None,
+            None,
+            WorkerStats::new(config.layer_count(), config.max_iteration()),
+            None,
+        );
+        let c = Complex::new(0.5, 1.0);
+        state.orbit_buffer = vec![c; 4];
+        assert_eq!(state.record_orbit(), 4);
+
+        let (x, y) = state.project(&c).unwrap();
+        let channels = state.channels.into_channels();
+        assert_eq!(channels.len(), 2);
+        assert_eq!(channels["weight_0"].value(x, y), 1.5);
+        assert_eq!(channels["weight_1"].value(x, y), 0.0);
+    }
+
+    #[test]
+    fn escape_channels_are_recorded_per_layer() {
+        let config: SampleConfig = serde_json::from_str(
+            r#"{
+                "cutoffs": [10, 100],
+                "view": { "width": 100, "height": 100, "zoom": 0.25, "center": [0.0, 0.0] },
+                "escape_channels": true
+            }"#,
+        )
+        .unwrap();
+
+        let mut state = WorkerState::new(
+            &config,
+            WorkerGrids::local(&config, false),
+            StopSwitch::without_handlers(),
+            None,
+            None,
+            WorkerStats::new(config.layer_count(), config.max_iteration()),
+            None,
+        );
+        let c = Complex::new(0.5, 1.0);
+        state.orbit_buffer = vec![c; 4];
+        state.escaped_z = Complex::new(0.0, -3.0);
+        assert_eq!(state.record_orbit(), 4);
+
+        let (x, y) = state.project(&c).unwrap();
+        let channels = state.channels.into_channels();
+        assert_eq!(channels.len(), 6);
+        assert_eq!(channels["escape_cos_0"].value(x, y), 0.0);
+        assert_eq!(channels["escape_sin_0"].value(x, y), -4.0);
+        assert!(channels["escape_time_0"].value(x, y) > 4.0);
+        assert_eq!(channels["escape_time_1"].value(x, y), 0.0);
+    }
+
+    #[test]
+    fn spectral_colors_are_recorded() {
+        let config: SampleConfig = serde_json::from_str(
+            r#"{
+                "cutoffs": [10, 100],
+                "view": { "width": 100, "height": 100, "zoom": 0.25, "center": [0.0, 0.0] },
+                "spectral": { "max_length": 100, "palette": [[0, 0, 1], [1, 0, 0]] }
+            }"#,
+        )
+        .unwrap();
+
+        // Colors blend the palette by log length
+        let spectral = config.spectral.as_ref().unwrap();
+        assert_eq!(spectral.color(1), [0.0, 0.0, 1.0]);
+        assert_eq!(spectral.color(1000), [1.0, 0.0, 0.0]);
+        assert_eq!(spectral.color(10), [0.5, 0.0, 0.5]);
+
+        let mut state = WorkerState::new(
+            &config,
+            WorkerGrids::local(&config, false),
+            StopSwitch::without_handlers(),
+            None,
+            None,
+            WorkerStats::new(config.layer_count(), config.max_iteration()),
+            None,
+        );
+        let c = Complex::new(0.5, 1.0);
+        state.orbit_buffer = vec![c; 4];
+        assert_eq!(state.record_orbit(), 4);
+
+        // Spectral colors are recorded once per point, whatever the layer
+        let (x, y) = state.project(&c).unwrap();
+        let channels = state.channels.into_channels();
+        assert_eq!(channels.len(), 3);
+        let color = spectral.color(4);
+        for (name, value) in SpectralConfig::CHANNELS.iter().zip(color) {
+            assert!((channels[*name].value(x, y) - 4.0 * value).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn c_density_is_recorded() {
+        let config: SampleConfig = serde_json::from_str(
+            r#"{
+                "cutoffs": [100],
+                "view": { "width": 100, "height": 100, "zoom": 0.25, "center": [0.0, 0.0] },
+                "symmetry": true,
+                "c_density": { "width": 20, "height": 10, "zoom": 0.25, "center": [0.0, 0.0] }
+            }"#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+
+        let mut state = WorkerState::new(
+            &config,
+            WorkerGrids::local(&config, false),
+            StopSwitch::without_handlers(),
+            None,
+            None,
+            WorkerStats::new(config.layer_count(), config.max_iteration()),
+            None,
+        );
+        let c = Complex::new(0.5, 0.5);
+        state.record_c_density(&c, 3);
+        state.record_c_density(&Complex::new(50.0, 0.0), 3);
+
+        // With symmetry the conjugate is recorded as well, samples outside the window are not
+        let window = config.c_density.as_ref().unwrap();
+        let channels = state.channels.into_channels();
+        let grid = &channels[C_DENSITY_CHANNEL];
+        assert_eq!(grid.dimensions(), (20, 10));
+        let (x, y) = project_onto_view(window, &c).unwrap();
+        let (cx, cy) = project_onto_view(window, &c.conj()).unwrap();
+        assert_eq!(grid.value(x, y), 3.0);
+        assert_eq!(grid.value(cx, cy), 3.0);
+        assert_eq!(grid.data().iter().sum::<f64>(), 6.0);
+    }
+
+    #[test]
+    fn masks_weight_recording() {
+        let config: SampleConfig = serde_json::from_str(
+            r#"{
+                "cutoffs": [20],
+                "view": { "width": 4, "height": 4, "zoom": 0.25, "center": [0.0, 0.0] },
+                "weighting": { "type": "orbit_length", "exponent": 0.0 }
+            }"#,
+        )
+        .unwrap();
+        let state = WorkerState::new(
+            &config,
+            WorkerGrids::local(&config, false),
+            StopSwitch::without_handlers(),
+            None,
+            None,
+            WorkerStats::new(config.layer_count(), config.max_iteration()),
+            None,
+        );
+
+        // Only the cell c lands in is lit, at about a quarter
+        let c = Complex::new(0.5, 1.0);
+        let (x, y) = state.project(&c).unwrap();
+        let path = std::env::temp_dir().join(format!("escape_weights_{}.png", std::process::id()));
+        let mut image = image::GrayImage::new(4, 4);
+        image.put_pixel(x as u32, y as u32, image::Luma([64]));
+        image.save(&path).unwrap();
+        let mask_config = crate::config::MaskConfig {
+            path: path.clone(),
+            hash: None,
+        };
+        let mut masked = config.clone();
+        masked.mask = Some(mask_config.clone());
+        let mut rehashed = masked.clone();
+        rehashed.mask.as_mut().unwrap().hash = Some("0".to_string());
+        assert!(!config.compatible(&masked));
+        assert!(!masked.compatible(&rehashed));
+        rehashed.symmetry = true;
+        assert!(rehashed.validate().is_err());
+        let mask = Mask::load(&mask_config, &config.view).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut state = state.with_mask(Arc::new(mask));
+
+        // Ten points weigh 2.5, two whole counts with the rest carried over
+        let value = 64.0 / 255.0;
+        state.orbit_buffer = vec![c; 10];
+        assert_eq!(state.record_orbit(), 10);
+        assert_eq!(state.mask_weight(), Some(10.0 * value));
+        assert_eq!(
+            state.contribution(10, state.mask_weight()),
+            10.0 * value / 20.0
+        );
+        let WorkerGrids::Local(grids) = &state.grids else {
+            panic!("Expected local grids");
+        };
+        assert_eq!(grids[0].value(x, y), 2);
+        let channels = state.channels.clone().into_channels();
+        assert!((channels["weight_0"].value(x, y) - 10.0 * value).abs() < 1e-12);
+
+        // Orbits that only cross black cells are not recorded
+        let black = Complex::new(-0.5, -1.0);
+        assert!(state.project(&black).is_some_and(|cell| cell != (x, y)));
+        state.orbit_buffer = vec![black; 10];
+        assert_eq!(state.orbit_intersections(), 0);
+        assert_eq!(state.record_orbit(), 0);
+    }
+
+    #[test]
+    fn orbit_traps_filter_orbits() {
+        let config: SampleConfig = serde_json::from_str(
+            r#"{
+                "cutoffs": [100],
+                "view": { "width": 100, "height": 100, "zoom": 0.25, "center": [0.0, 0.0] },
+                "orbit_traps": {
+                    "traps": [
+                        { "type": "circle", "center": [0.0, 0.0], "radius": 1.0 },
+                        { "type": "line", "point": [0.0, 0.5], "angle": 0.0 },
+                        { "type": "cross", "center": [1.5, 1.5] },
+                        { "type": "box", "min": [-1.5, -1.5], "max": [-1.0, -1.0] }
+                    ],
+                    "threshold": 0.05,
+                    "after_hit": true,
+                    "distance_channel": true
+                }
+            }"#,
+        )
+        .unwrap();
+        let mut wider = config.clone();
+        wider.orbit_traps.as_mut().unwrap().threshold = 0.1;
+        assert!(!config.compatible(&wider));
+        assert!(config.validate().is_ok());
+
+        let orbit_traps = config.orbit_traps.as_ref().unwrap();
+        let distances: Vec<f64> = orbit_traps
+            .traps
+            .iter()
+            .map(|trap| trap.distance(&Complex::new(-1.2, 0.3)))
+            .collect();
+        assert!((distances[0] - (1.53f64.sqrt() - 1.0)).abs() < 1e-12);
+        assert!((distances[1] - 0.2).abs() < 1e-12);
+        assert!((distances[2] - 1.2).abs() < 1e-12);
+        assert!((distances[3] - 1.3).abs() < 1e-12);
+
+        let mut state = WorkerState::new(
+            &config,
+            WorkerGrids::local(&config, false),
+            StopSwitch::without_handlers(),
+            None,
+            None,
+            WorkerStats::new(config.layer_count(), config.max_iteration()),
+            None,
+        );
+        let missed = Complex::new(0.3, 0.2);
+        let trapped = Complex::new(0.3, 0.51);
+        state.orbit_buffer = vec![missed; 5];
+        assert_eq!(state.record_orbit(), 0);
+
+        // Only the points from the first hit on are recorded
+        state.orbit_buffer = vec![missed, missed, trapped, missed];
+        assert_eq!(state.orbit_window(), Some((2, 1)));
+        assert_eq!(state.record_orbit(), 2);
+        let (x, y) = state.project(&missed).unwrap();
+        let channels = state.channels.into_channels();
+        assert!((channels["trap_distance_0"].value(x, y) - 0.3).abs() < 1e-12);
+    }
+
+    #[test]
+    fn budgets_are_reproducible() {
+        let budget = WorkBudget {
+            samples: Some(7),
+            metro_instances: None,
+        };
+        assert_eq!(budget.share(0, 3).samples, Some(3));
+        assert_eq!(budget.share(2, 3).samples, Some(2));
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .unwrap();
+
+        let run = || {
+            runtime.block_on(async {
+                let config: SampleConfig = serde_json::from_str(
+                    r#"{
+                        "cutoffs": [20],
+                        "view": { "width": 40, "height": 40, "zoom": 0.25, "center": [0.0, 0.0] },
+                        "samples": 500,
+                        "warm_up_samples": 10
+                    }"#,
+                )
+                .unwrap();
+                let budget = WorkBudget {
+                    samples: Some(5000),
+                    metro_instances: Some(100),
+                };
+                let switch = StopSwitch::without_handlers();
+                let sampler = Sampler::start(
+                    Arc::new(config),
+                    2,
+                    GridStrategy::Local,
+                    Some(3),
+                    budget,
+                    false,
+                    switch.clone(),
+                )
+                .unwrap();
+                let result = sampler.finish().await.unwrap();
+                assert!(switch.read().stop());
+                result[0].to_dense()
+            })
+        };
+
+        let first = run();
+        assert!(first.data().iter().sum::<u64>() > 0);
+        assert_eq!(first.data(), run().data());
+    }
+
+    #[test]
+    fn budgets_end_on_unreachable_views() {
+        let config: SampleConfig = serde_json::from_str(
+            r#"{
+                "cutoffs": [20],
+                "view": { "width": 40, "height": 40, "zoom": 0.25, "center": [100.0, 100.0] },
+                "samples": 500,
+                "warm_up_samples": 10,
+                "initial_search_depth": 10
+            }"#,
+        )
+        .unwrap();
+        let switch = StopSwitch::without_handlers();
+        let budget = WorkBudget {
+            samples: Some(10),
+            metro_instances: None,
+        };
+        let state = WorkerState::new(
+            &config,
+            WorkerGrids::local(&config, false),
+            switch.clone(),
+            None,
+            None,
+            WorkerStats::new(config.layer_count(), config.max_iteration()),
+            None,
+        )
+        .with_budget(budget, Arc::new(AtomicUsize::new(1)));
+
+        let state = state.run_worker();
+        assert_eq!(state.samples_taken, 0);
+        assert!(switch.read().stop());
+    }
+
+    #[test]
+    fn snapshot_while_sampling() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let config: SampleConfig = serde_json::from_str(
+                r#"{
+                    "cutoffs": [20],
+                    "view": { "width": 40, "height": 40, "zoom": 0.25, "center": [0.0, 0.0] },
+                    "samples": 500,
+                    "warm_up_samples": 10
+                }"#,
+            )
+            .unwrap();
+            let switch = StopSwitch::without_handlers();
+            let sampler = Sampler::start(
+                Arc::new(config),
+                2,
+                GridStrategy::Local,
+                Some(1),
+                WorkBudget::default(),
+                false,
+                switch.clone(),
+            )
+            .unwrap();
+
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let first = sampler.snapshot().await.unwrap().unwrap();
+            let second = sampler.snapshot().await.unwrap().unwrap();
+            let total = |grids: &[HistogramGrid]| grids[0].to_dense().data().iter().sum::<u64>();
+            assert!(total(&first) > 0);
+            assert!(total(&second) >= total(&first));
+
+            switch.write().trigger("Test complete");
+            let result = sampler.finish().await.unwrap();
+            assert!(total(&result) >= total(&second));
+        });
+    }
+
     #[test]
     fn test_project_1() {
         let config = ViewConfig {
@@ -719,7 +2430,7 @@ mod tests {
         assert_eq!(project_onto_view(&config, &Complex::new(100.0, 0.5)), None);
         assert_eq!(project_onto_view(&config, &Complex::new(-0.6, 0.8)), None);
         assert_eq!(
-            project_onto_view(&config, &Complex::new(std::f64::NAN, 0.8)),
+            project_onto_view(&config, &Complex::new(f64::NAN, 0.8)),
             None
         );
     }
@@ -759,7 +2470,7 @@ mod tests {
         assert_eq!(project_onto_view(&config, &Complex::new(100.0, 0.5)), None);
         assert_eq!(project_onto_view(&config, &Complex::new(-0.6, 0.8)), None);
         assert_eq!(
-            project_onto_view(&config, &Complex::new(std::f64::NAN, 0.8)),
+            project_onto_view(&config, &Complex::new(f64::NAN, 0.8)),
             None
         );
     }
@@ -0,0 +1,672 @@
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+use tracing::{error, info};
+
+use crate::cli_options::ServeOptions;
+use crate::config::{DrawConfig, SampleConfig};
+use crate::convergence::{self, stop_on_convergence};
+use crate::draw;
+use crate::histogram_result::HistogramResult;
+use crate::http::{self, Request, Response};
+use crate::mask;
+use crate::memory::{self, GridStrategy};
+use crate::sample::{duration_handler, ArcSwitch, Sampler, StopSwitch, WorkBudget};
+use crate::stats::{ArcStats, StatsSnapshot};
+use crate::types::{EscapeError, EscapeResult, MemorySize};
+
+/// Body of a request to sample a histogram
+#[derive(Deserialize, Debug)]
+struct SampleJobRequest {
+    config: SampleConfig,
+
+    /// Workers to sample with, counted against the server's worker budget
+    #[serde(default = "SampleJobRequest::default_workers")]
+    workers: usize,
+
+    /// Seconds to sample for, otherwise the job runs until cancelled or its config converges
+    duration: Option<u64>,
+
+    seed: Option<u64>,
+}
+
+impl SampleJobRequest {
+    fn default_workers() -> usize {
+        1
+    }
+}
+
+/// Body of a request to draw the histogram of a previous sample job
+#[derive(Deserialize, Debug)]
+struct DrawJobRequest {
+    config: DrawConfig,
+    histogram_job: usize,
+}
+
+#[derive(Debug)]
+enum JobSpec {
+    Sample(Box<SampleJobRequest>),
+    Draw(DrawJobRequest),
+}
+
+#[derive(Serialize, Copy, Clone, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Cancelled,
+    Failed,
+}
+
+#[derive(Debug)]
+struct Job {
+    id: usize,
+    spec: Arc<JobSpec>,
+    workers: usize,
+    status: JobStatus,
+    stop_switch: ArcSwitch,
+    cancelled: bool,
+
+    /// How a sample job's workers store their grids, and its estimated peak memory
+    strategy: GridStrategy,
+    memory: u64,
+
+    /// Counters of a sample job's workers, once it has started
+    stats: Vec<ArcStats>,
+    started: Option<Instant>,
+    finished: Option<Instant>,
+    error: Option<String>,
+    result: Option<PathBuf>,
+}
+
+/// What the API reports about a job
+#[derive(Serialize, Debug)]
+struct JobReport {
+    id: usize,
+    kind: &'static str,
+    status: JobStatus,
+    workers: usize,
+    elapsed_seconds: Option<f64>,
+    progress: Option<f64>,
+
+    /// Samples taken so far, and hits recorded to each layer, for sample jobs that have started
+    samples: Option<u64>,
+    layer_hits: Option<Vec<u64>>,
+    error: Option<String>,
+    result: Option<String>,
+}
+
+impl Job {
+    fn report(&self) -> JobReport {
+        let elapsed = self
+            .started
+            .map(|started| self.finished.unwrap_or_else(Instant::now) - started);
+        let progress = match (&*self.spec, self.status) {
+            (_, JobStatus::Completed) => Some(1.0),
+            (JobSpec::Sample(request), JobStatus::Running) => request
+                .duration
+                .zip(elapsed)
+                .map(|(duration, elapsed)| (elapsed.as_secs_f64() / duration as f64).min(1.0)),
+            _ => None,
+        };
+        let stats = match self.stats.is_empty() {
+            true => None,
+            false => Some(StatsSnapshot::total(&self.stats)),
+        };
+
+        JobReport {
+            id: self.id,
+            kind: match &*self.spec {
+                JobSpec::Sample(_) => "sample",
+                JobSpec::Draw(_) => "draw",
+            },
+            status: self.status,
+            workers: self.workers,
+            elapsed_seconds: elapsed.map(|e| e.as_secs_f64()),
+            progress,
+            samples: stats.as_ref().map(StatsSnapshot::samples),
+            layer_hits: stats.map(|stats| stats.layer_hits),
+            error: self.error.clone(),
+            result: self
+                .result
+                .as_ref()
+                .map(|_| format!("/jobs/{}/result", self.id)),
+        }
+    }
+}
+
+/// Jobs are run in the order they are submitted, as workers become available
+struct Server {
+    jobs: Mutex<Vec<Job>>,
+    worker_budget: usize,
+
+    /// Running sample jobs share this much memory, by their estimated peak
+    memory_budget: Option<u64>,
+    data_dir: PathBuf,
+    wake: Notify,
+}
+
+impl Server {
+    fn submit(&self, spec: JobSpec) -> Result<usize, String> {
+        let mut strategy = GridStrategy::Local;
+        let mut memory = 0;
+        let workers = match &spec {
+            JobSpec::Sample(request) => {
+                request.config.validate().map_err(|e| format!("{:?}", e))?;
+                let mut ladder_size = 1;
+                if let Some(tempering) = &request.config.tempering {
+                    tempering
+                        .validate(request.workers)
+                        .map_err(|e| format!("{:?}", e))?;
+                    ladder_size = tempering.ladder_size();
+                }
+                if request.config.convergence.is_some() {
+                    convergence::check_workers(request.workers / ladder_size, false)
+                        .map_err(|e| format!("{:?}", e))?;
+                }
+                if let Some(mask) = &request.config.mask {
+                    check_mask_path(&mask.path)?;
+                }
+                if let Some(budget) = self.memory_budget {
+                    let (fit, estimate) =
+                        memory::fit_budget(&request.config, request.workers, strategy, budget)
+                            .map_err(|e| format!("{:?}", e))?;
+                    strategy = fit;
+                    memory = estimate.peak();
+                }
+                request.workers
+            }
+            JobSpec::Draw(request) => {
+                let jobs = self.jobs.lock();
+                match jobs.get(request.histogram_job).map(|job| &*job.spec) {
+                    Some(JobSpec::Sample(_)) => {}
+                    _ => return Err(format!("Job {} is not a sample job", request.histogram_job)),
+                }
+                1
+            }
+        };
+
+        if workers == 0 || workers > self.worker_budget {
+            return Err(format!(
+                "Jobs must use between 1 and {} workers",
+                self.worker_budget
+            ));
+        }
+
+        let mut jobs = self.jobs.lock();
+        let id = jobs.len();
+        jobs.push(Job {
+            id,
+            spec: Arc::new(spec),
+            workers,
+            status: JobStatus::Queued,
+            stop_switch: StopSwitch::without_handlers(),
+            cancelled: false,
+            strategy,
+            memory,
+            stats: Vec::new(),
+            started: None,
+            finished: None,
+            error: None,
+            result: None,
+        });
+        drop(jobs);
+
+        info!(id, workers, "Job queued");
+        self.wake.notify_one();
+        Ok(id)
+    }
+
+    fn cancel(&self, id: usize) -> Option<JobReport> {
+        let mut jobs = self.jobs.lock();
+        let job = jobs.get_mut(id)?;
+        match job.status {
+            JobStatus::Queued => {
+                job.status = JobStatus::Cancelled;
+                job.cancelled = true;
+            }
+            JobStatus::Running => {
+                job.cancelled = true;
+                job.stop_switch.write().trigger("Job cancelled");
+            }
+            _ => {}
+        }
+        Some(job.report())
+    }
+
+    fn cancel_all(&self) {
+        let ids: Vec<usize> = self.jobs.lock().iter().map(|job| job.id).collect();
+        for id in ids {
+            self.cancel(id);
+        }
+    }
+
+    fn running_jobs(&self) -> usize {
+        self.jobs
+            .lock()
+            .iter()
+            .filter(|job| job.status == JobStatus::Running)
+            .count()
+    }
+
+    /// Start queued jobs in order, until the next one doesn't fit in the worker or memory budget
+    /// Draw jobs wait for their sample job to finish, without holding up the jobs after them
+    fn start_ready_jobs(server: &Arc<Server>) {
+        let mut jobs = server.jobs.lock();
+        let running = || jobs.iter().filter(|job| job.status == JobStatus::Running);
+        let mut busy: usize = running().map(|job| job.workers).sum();
+        let mut busy_memory: u64 = running().map(|job| job.memory).sum();
+
+        for index in 0..jobs.len() {
+            if jobs[index].status != JobStatus::Queued {
+                continue;
+            }
+            if let JobSpec::Draw(request) = &*jobs[index].spec {
+                let histogram_status = jobs[request.histogram_job].status;
+                if matches!(histogram_status, JobStatus::Queued | JobStatus::Running) {
+                    continue;
+                }
+            }
+
+            let job = &mut jobs[index];
+            if busy + job.workers > server.worker_budget {
+                break;
+            }
+            if server
+                .memory_budget
+                .is_some_and(|budget| busy_memory + job.memory > budget)
+            {
+                break;
+            }
+
+            busy += job.workers;
+            busy_memory += job.memory;
+            job.status = JobStatus::Running;
+            job.started = Some(Instant::now());
+            info!(id = job.id, "Job started");
+            tokio::spawn(run_job(
+                server.clone(),
+                job.id,
+                job.spec.clone(),
+                job.stop_switch.clone(),
+            ));
+        }
+    }
+
+    fn histogram_path(&self, id: usize) -> Option<PathBuf> {
+        self.jobs.lock().get(id).and_then(|job| job.result.clone())
+    }
+}
+
+/// Mask paths in requests are relative to the data directory, and may not leave it
+fn check_mask_path(path: &Path) -> Result<(), String> {
+    let inside = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if path.as_os_str().is_empty() || !inside {
+        return Err(format!(
+            "Mask path {} must be relative to the data directory, without ..",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+async fn run_sample_job(
+    server: &Server,
+    id: usize,
+    request: &SampleJobRequest,
+    stop_switch: ArcSwitch,
+) -> Result<PathBuf, EscapeError> {
+    if let Some(seconds) = request.duration {
+        tokio::spawn(duration_handler(stop_switch.clone(), seconds));
+    }
+
+    let mut config = request.config.clone();
+    if let Some(mask) = &mut config.mask {
+        mask.path = server.data_dir.join(&mask.path);
+    }
+    mask::pin_hash(&mut config)?;
+    let config = Arc::new(config);
+    let strategy = server.jobs.lock()[id].strategy;
+    let sampler = Sampler::start(
+        config.clone(),
+        request.workers,
+        strategy,
+        request.seed,
+        WorkBudget::default(),
+        false,
+        stop_switch.clone(),
+    )?;
+    let stats = sampler.stats();
+    server.jobs.lock()[id].stats = stats.clone();
+    if let Some(convergence) = &config.convergence {
+        stop_on_convergence(&sampler, convergence, &stop_switch).await;
+    }
+    let (grids, channels) = sampler.finish_with_channels().await?;
+    let spectrum = StatsSnapshot::total(&stats).orbit_spectrum();
+
+    let path = server.data_dir.join(format!("job_{}_histogram.json", id));
+    let save_path = path.clone();
+    tokio::task::spawn_blocking(move || {
+        HistogramResult::save_with(&config, &grids, Some(&spectrum), &channels, &save_path)
+    })
+    .await??;
+    Ok(path)
+}
+
+async fn run_draw_job(
+    server: &Server,
+    id: usize,
+    request: &DrawJobRequest,
+) -> Result<PathBuf, EscapeError> {
+    let histogram_path = server
+        .histogram_path(request.histogram_job)
+        .ok_or_else(|| {
+            EscapeError::Http(format!(
+                "Job {} has no histogram result",
+                request.histogram_job
+            ))
+        })?;
+
+    let path = server.data_dir.join(format!("job_{}.png", id));
+    let image_path = path.clone();
+    let draw_config = request.config.clone();
+    tokio::task::spawn_blocking(move || -> EscapeResult {
+        let histogram = HistogramResult::load(&histogram_path)?;
+        let image = draw::render(
+            &draw_config,
+            &histogram.config,
+            &histogram.grids,
+            &histogram.channels,
+        )?;
+        image.save(&image_path)?;
+        Ok(())
+    })
+    .await??;
+    Ok(path)
+}
+
+async fn run_job(server: Arc<Server>, id: usize, spec: Arc<JobSpec>, stop_switch: ArcSwitch) {
+    let result = match &*spec {
+        JobSpec::Sample(request) => run_sample_job(&server, id, request, stop_switch).await,
+        JobSpec::Draw(request) => run_draw_job(&server, id, request).await,
+    };
+
+    {
+        let mut jobs = server.jobs.lock();
+        let job = &mut jobs[id];
+        job.finished = Some(Instant::now());
+        match result {
+            Ok(path) => {
+                job.result = Some(path);
+                job.status = if job.cancelled {
+                    JobStatus::Cancelled
+                } else {
+                    JobStatus::Completed
+                };
+                info!(id, "Job finished");
+            }
+            Err(e) => {
+                error!(id, "Job failed: {:?}", e);
+                job.error = Some(format!("{:?}", e));
+                job.status = JobStatus::Failed;
+            }
+        }
+    }
+
+    server.wake.notify_one();
+}
+
+async fn schedule(server: Arc<Server>, stop_switch: ArcSwitch) {
+    while !stop_switch.read().stop() {
+        Server::start_ready_jobs(&server);
+        let _ = tokio::time::timeout(Duration::from_secs(1), server.wake.notified()).await;
+    }
+}
+
+fn parse_body<T: serde::de::DeserializeOwned>(request: &Request) -> Result<T, Response> {
+    serde_json::from_slice(&request.body)
+        .map_err(|e| Response::error(400, &format!("Invalid request body: {}", e)))
+}
+
+async fn handle(server: Arc<Server>, request: Request) -> Response {
+    let segments = request.segments();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", []) | ("GET", ["jobs"]) => {
+            let reports: Vec<JobReport> = server.jobs.lock().iter().map(Job::report).collect();
+            Response::json(200, &reports)
+        }
+        ("POST", ["jobs", kind]) => {
+            let spec = match *kind {
+                "sample" => parse_body(&request).map(|r| JobSpec::Sample(Box::new(r))),
+                "draw" => parse_body(&request).map(JobSpec::Draw),
+                _ => return Response::not_found(),
+            };
+            let spec = match spec {
+                Ok(spec) => spec,
+                Err(response) => return response,
+            };
+            match server.submit(spec) {
+                Ok(id) => Response::json(201, &server.jobs.lock()[id].report()),
+                Err(msg) => Response::error(400, &msg),
+            }
+        }
+        ("GET", ["jobs", id]) => match id
+            .parse::<usize>()
+            .ok()
+            .and_then(|id| server.jobs.lock().get(id).map(Job::report))
+        {
+            Some(report) => Response::json(200, &report),
+            None => Response::not_found(),
+        },
+        ("GET", ["jobs", id, "result"]) => {
+            let path = match id
+                .parse::<usize>()
+                .ok()
+                .and_then(|id| server.histogram_path(id))
+            {
+                Some(path) => path,
+                None => return Response::error(404, "Job has no result"),
+            };
+            let content_type = if path.extension().is_some_and(|e| e == "png") {
+                "image/png"
+            } else {
+                "application/json"
+            };
+            match tokio::fs::read(&path).await {
+                Ok(body) => Response {
+                    status: 200,
+                    content_type,
+                    body,
+                },
+                Err(e) => Response::error(500, &format!("Failed to read result: {}", e)),
+            }
+        }
+        ("POST", ["jobs", id, "cancel"]) | ("DELETE", ["jobs", id]) => {
+            match id.parse::<usize>().ok().and_then(|id| server.cancel(id)) {
+                Some(report) => Response::json(200, &report),
+                None => Response::not_found(),
+            }
+        }
+        (_, ["jobs", ..]) | (_, []) => Response::error(405, "Method not allowed"),
+        _ => Response::not_found(),
+    }
+}
+
+async fn async_serve(cli_options: &ServeOptions) -> EscapeResult {
+    let logger_builder = tracing_subscriber::fmt()
+        .with_timer(tracing_subscriber::fmt::time::uptime())
+        .with_thread_ids(true)
+        .with_max_level(&cli_options.verbosity);
+
+    if cli_options.pretty_logging {
+        logger_builder.pretty().init()
+    } else {
+        logger_builder.init();
+    }
+
+    std::fs::create_dir_all(&cli_options.data_dir)?;
+    let server = Arc::new(Server {
+        jobs: Mutex::new(Vec::new()),
+        worker_budget: cli_options.workers,
+        memory_budget: cli_options.max_memory.map(|MemorySize(budget)| budget),
+        data_dir: cli_options.data_dir.clone(),
+        wake: Notify::new(),
+    });
+
+    let listener = TcpListener::bind(&cli_options.listen).await?;
+    info!("Serving on {}", listener.local_addr()?);
+
+    let stop_switch = StopSwitch::new(&None).await;
+    let scheduler = tokio::spawn(schedule(server.clone(), stop_switch.clone()));
+    let handler_server = server.clone();
+    http::serve(
+        listener,
+        move |request| handle(handler_server.clone(), request),
+        stop_switch,
+    )
+    .await?;
+    scheduler.await?;
+
+    info!("Cancelling jobs");
+    server.cancel_all();
+    while server.running_jobs() > 0 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    Ok(())
+}
+
+pub fn run_serve(serve_options: &ServeOptions) -> EscapeResult {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async_serve(serve_options))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str, body: &str) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    async fn wait_for(server: &Arc<Server>, id: usize, status: JobStatus) {
+        for _ in 0..200 {
+            if server.jobs.lock()[id].status == status {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("Job {} did not reach {:?}", id, status);
+    }
+
+    #[test]
+    fn job_lifecycle() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let data_dir = std::env::temp_dir().join(format!("escape_serve_{}", std::process::id()));
+            std::fs::create_dir_all(&data_dir).unwrap();
+            let server = Arc::new(Server {
+                jobs: Mutex::new(Vec::new()),
+                worker_budget: 2,
+                memory_budget: Some(1 << 20),
+                data_dir: data_dir.clone(),
+                wake: Notify::new(),
+            });
+            let stop_switch = StopSwitch::without_handlers();
+            tokio::spawn(schedule(server.clone(), stop_switch.clone()));
+
+            let config = r#"{
+                "cutoffs": [20],
+                "view": { "width": 40, "height": 40, "zoom": 0.25, "center": [0.0, 0.0] },
+                "samples": 500,
+                "warm_up_samples": 10
+            }"#;
+
+            // Too many workers for the budget
+            let body = format!(r#"{{ "config": {}, "workers": 3 }}"#, config);
+            let response = handle(server.clone(), request("POST", "/jobs/sample", &body)).await;
+            assert_eq!(response.status, 400);
+
+            // Too much memory for the budget, even with shared grids
+            let large = config.replace(r#""width": 40, "height": 40"#, r#""width": 400, "height": 400"#);
+            let body = format!(r#"{{ "config": {}, "workers": 1 }}"#, large);
+            let response = handle(server.clone(), request("POST", "/jobs/sample", &body)).await;
+            assert_eq!(response.status, 400);
+
+            // Masks must come from the data directory
+            for path in ["/etc/passwd", "../mask.png"] {
+                let masked = config.replace(
+                    r#""samples""#,
+                    &format!(r#""mask": {{ "path": "{}" }}, "samples""#, path),
+                );
+                let body = format!(r#"{{ "config": {}, "workers": 1 }}"#, masked);
+                let response = handle(server.clone(), request("POST", "/jobs/sample", &body)).await;
+                assert_eq!(response.status, 400);
+            }
+
+            // Runs until cancelled, but cancelling still keeps the result
+            let body = format!(r#"{{ "config": {}, "workers": 2 }}"#, config);
+            let response = handle(server.clone(), request("POST", "/jobs/sample", &body)).await;
+            assert_eq!(response.status, 201);
+            wait_for(&server, 0, JobStatus::Running).await;
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            let report = server.jobs.lock()[0].report();
+            assert!(report.progress.is_none());
+            assert!(report.samples.unwrap() > 0);
+            assert_eq!(report.layer_hits.unwrap().len(), 1);
+
+            // Waits for the first job's workers
+            let body = format!(r#"{{ "config": {}, "duration": 1 }}"#, config);
+            handle(server.clone(), request("POST", "/jobs/sample", &body)).await;
+            assert_eq!(server.jobs.lock()[1].status, JobStatus::Queued);
+
+            // Waits for the histogram it draws, even once there are workers for it
+            let draw = r#"{
+                "config": { "colors": [[255, 255, 255]], "powers": [1.0], "background_color": [0.0, 0.0, 0.0] },
+                "histogram_job": 1
+            }"#;
+            let response = handle(server.clone(), request("POST", "/jobs/draw", draw)).await;
+            assert_eq!(response.status, 201);
+
+            let response = handle(server.clone(), request("POST", "/jobs/0/cancel", "")).await;
+            assert_eq!(response.status, 200);
+            wait_for(&server, 0, JobStatus::Cancelled).await;
+            wait_for(&server, 1, JobStatus::Running).await;
+            assert_eq!(server.jobs.lock()[2].status, JobStatus::Queued);
+            wait_for(&server, 1, JobStatus::Completed).await;
+            wait_for(&server, 2, JobStatus::Completed).await;
+
+            let response = handle(server.clone(), request("GET", "/jobs/2/result", "")).await;
+            assert_eq!(response.content_type, "image/png");
+            assert_eq!(&response.body[1..4], b"PNG");
+
+            let response = handle(server.clone(), request("GET", "/jobs/7", "")).await;
+            assert_eq!(response.status, 404);
+
+            stop_switch.write().trigger("Test complete");
+            std::fs::remove_dir_all(&data_dir).unwrap();
+        });
+    }
+}
@@ -0,0 +1,141 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::channels::Channels;
+use crate::config::{DrawConfig, SampleConfig};
+use crate::draw;
+use crate::histogram_result::HistogramResult;
+use crate::sample::{ArcSwitch, Sampler};
+use crate::types::EscapeResult;
+
+/// Where snapshots of a running sampler are written
+#[derive(Clone, Debug)]
+pub struct SnapshotOutputs {
+    pub histogram: PathBuf,
+
+    /// A preview image is drawn along with the histogram if a draw config is given
+    pub preview: Option<(DrawConfig, PathBuf)>,
+}
+
+impl SnapshotOutputs {
+    /// Without an explicit path, a run writing to result.json snapshots to result_snapshot.json,
+    /// and previews are written next to the histogram as png
+    pub fn new(
+        output: &Path,
+        snapshot_output: Option<&Path>,
+        preview_config: Option<DrawConfig>,
+    ) -> SnapshotOutputs {
+        let histogram = match snapshot_output {
+            Some(path) => path.to_path_buf(),
+            None => {
+                let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+                output.with_file_name(format!("{}_snapshot.json", stem))
+            }
+        };
+        let preview = preview_config.map(|config| (config, histogram.with_extension("png")));
+        SnapshotOutputs { histogram, preview }
+    }
+}
+
+#[cfg(unix)]
+pub type UserSignal = tokio::signal::unix::Signal;
+
+#[cfg(not(unix))]
+pub type UserSignal = ();
+
+/// Listen for SIGUSR1, set up before sampling starts so that a failure doesn't cost a run
+#[cfg(unix)]
+pub fn user_signal() -> std::io::Result<UserSignal> {
+    use tokio::signal::unix::{signal, SignalKind};
+    signal(SignalKind::user_defined1())
+}
+
+#[cfg(not(unix))]
+pub fn user_signal() -> std::io::Result<UserSignal> {
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn next_user_signal(signal: &mut UserSignal) {
+    signal.recv().await;
+}
+
+#[cfg(not(unix))]
+async fn next_user_signal(_signal: &mut UserSignal) {
+    std::future::pending::<()>().await
+}
+
+async fn write_snapshot(
+    sampler: &Sampler,
+    config: &Arc<SampleConfig>,
+    outputs: &SnapshotOutputs,
+) -> EscapeResult {
+    let grids = match sampler.snapshot().await? {
+        Some(grids) => grids,
+        None => {
+            info!("Sampling stopped before the snapshot was complete");
+            return Ok(());
+        }
+    };
+
+    let config = config.clone();
+    let outputs = outputs.clone();
+    tokio::task::spawn_blocking(move || -> EscapeResult {
+        HistogramResult::save(&config, &grids, &outputs.histogram)?;
+        info!("Snapshot written to {}", outputs.histogram.display());
+        if let Some((draw_config, path)) = &outputs.preview {
+            // Snapshots only hold counts, channels are collected when sampling finishes
+            draw::render(draw_config, &config, &grids, &Channels::new())?.save(path)?;
+            info!("Preview written to {}", path.display());
+        }
+        Ok(())
+    })
+    .await?
+}
+
+/// Write a snapshot whenever SIGUSR1 is received, or the trigger file appears,
+/// until the stop switch is triggered
+/// The trigger file is removed once it has been noticed
+pub async fn snapshot_on_demand(
+    sampler: &Sampler,
+    config: &Arc<SampleConfig>,
+    mut signal: UserSignal,
+    mut trigger_file: Option<&Path>,
+    outputs: &SnapshotOutputs,
+    stop_switch: &ArcSwitch,
+) {
+    let mut ticker = tokio::time::interval(Duration::from_millis(250));
+    loop {
+        let requested = tokio::select! {
+            _ = next_user_signal(&mut signal) => {
+                info!("SIGUSR1 received, taking a snapshot");
+                true
+            }
+            _ = ticker.tick() => {
+                if stop_switch.read().stop() {
+                    return;
+                }
+                match trigger_file {
+                    Some(path) if path.exists() => {
+                        info!("Found {}, taking a snapshot", path.display());
+                        if let Err(e) = std::fs::remove_file(path) {
+                            warn!("Failed to remove {}, no longer watching it: {:?}", path.display(), e);
+                            trigger_file = None;
+                        }
+                        true
+                    }
+                    _ => false,
+                }
+            }
+        };
+
+        // A failed snapshot shouldn't end the run
+        if requested {
+            if let Err(e) = write_snapshot(sampler, config, outputs).await {
+                warn!("Failed to write snapshot: {:?}", e);
+            }
+        }
+    }
+}
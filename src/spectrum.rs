@@ -0,0 +1,230 @@
+use serde::{Deserialize, Serialize};
+use std::io::BufReader;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::cli_options::CutoffsOptions;
+use crate::config::SampleConfig;
+use crate::memory::GridStrategy;
+use crate::sample::{Sampler, StopSwitch, WorkBudget};
+use crate::stats::StatsSnapshot;
+use crate::types::{EscapeError, EscapeResult};
+
+/// Orbit lengths are binned on a log scale, with this many bins per doubling
+pub const BINS_PER_OCTAVE: usize = 16;
+
+/// Histogram of the lengths of escaping orbits, weighted by how many times they hit the view
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OrbitSpectrum {
+    pub bins_per_octave: usize,
+    pub counts: Vec<u64>,
+}
+
+impl OrbitSpectrum {
+    pub fn from_counts(counts: Vec<u64>) -> OrbitSpectrum {
+        OrbitSpectrum {
+            bins_per_octave: BINS_PER_OCTAVE,
+            counts,
+        }
+    }
+
+    /// The bin an orbit length falls into
+    pub fn bin(orbit_len: usize) -> usize {
+        if orbit_len <= 1 {
+            0
+        } else {
+            ((orbit_len as f64).log2() * BINS_PER_OCTAVE as f64).floor() as usize
+        }
+    }
+
+    /// The number of bins needed for orbits up to max_len
+    pub fn bin_count(max_len: usize) -> usize {
+        OrbitSpectrum::bin(max_len) + 1
+    }
+
+    /// The longest orbit that falls into a bin
+    pub fn upper_edge(bin: usize) -> usize {
+        let mut edge = 2f64.powf((bin + 1) as f64 / BINS_PER_OCTAVE as f64).ceil() as usize;
+        while edge > 1 && OrbitSpectrum::bin(edge) > bin {
+            edge -= 1;
+        }
+        while OrbitSpectrum::bin(edge + 1) <= bin {
+            edge += 1;
+        }
+        edge
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn add(&mut self, other: &OrbitSpectrum) {
+        if self.counts.len() < other.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (count, other_count) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other_count;
+        }
+    }
+
+    /// The shortest orbit length that at least a fraction q of the weight is at or below,
+    /// rounded up to the edge of its bin
+    /// Returns None for an empty spectrum
+    pub fn quantile(&self, q: f64) -> Option<usize> {
+        let total = self.total();
+        if total == 0 {
+            return None;
+        }
+
+        let target = (q.clamp(0.0, 1.0) * total as f64).ceil().max(1.0) as u64;
+        let mut cumulative = 0;
+        for (bin, count) in self.counts.iter().enumerate() {
+            cumulative += count;
+            if cumulative >= target {
+                return Some(OrbitSpectrum::upper_edge(bin));
+            }
+        }
+        Some(OrbitSpectrum::upper_edge(self.counts.len() - 1))
+    }
+
+    /// Cutoffs that split the weight evenly between layers, ending at max_len
+    /// Layers that would repeat a cutoff are dropped
+    pub fn propose_cutoffs(&self, layers: usize, max_len: usize) -> Vec<usize> {
+        let mut cutoffs = Vec::with_capacity(layers);
+        for layer in 1..layers {
+            if let Some(cutoff) = self.quantile(layer as f64 / layers as f64) {
+                let cutoff = cutoff.min(max_len);
+                if cutoffs.last().is_none_or(|last| cutoff > *last) {
+                    cutoffs.push(cutoff);
+                }
+            }
+        }
+        if cutoffs.last().is_none_or(|last| max_len > *last) {
+            cutoffs.push(max_len);
+        }
+        cutoffs
+    }
+}
+
+async fn async_cutoffs(options: &CutoffsOptions) -> EscapeResult {
+    let logger_builder = tracing_subscriber::fmt()
+        .with_timer(tracing_subscriber::fmt::time::uptime())
+        .with_thread_ids(true)
+        .with_max_level(&options.verbosity);
+
+    if options.pretty_logging {
+        logger_builder.pretty().init()
+    } else {
+        logger_builder.init();
+    }
+
+    let mut config_reader = BufReader::new(std::fs::File::open(&options.config)?);
+    let mut config: SampleConfig = serde_json::from_reader(&mut config_reader)?;
+    config.validate()?;
+
+    if options.layers == 0 {
+        return Err(EscapeError::InvalidSampleConfig(
+            "At least one layer must be proposed".to_string(),
+        ));
+    }
+    let max_iteration = options.max_iteration.unwrap_or(config.max_iteration());
+
+    // Record every orbit in a single layer, so the spectrum covers the whole range
+    let mut pilot_config = config.clone();
+    pilot_config.cutoffs = vec![max_iteration];
+    pilot_config.layers = None;
+    pilot_config.convergence = None;
+    info!(
+        "Pilot run for {}s, recording orbits up to {}",
+        options.duration, max_iteration
+    );
+
+    let stop_switch = StopSwitch::new(&Some(options.duration)).await;
+    let sampler = Sampler::start(
+        Arc::new(pilot_config),
+        options.workers,
+        GridStrategy::Local,
+        options.seed,
+        WorkBudget::default(),
+        false,
+        stop_switch,
+    )?;
+    let stats = sampler.stats();
+    sampler.finish().await?;
+
+    let spectrum = StatsSnapshot::total(&stats).orbit_spectrum();
+    if spectrum.total() == 0 {
+        warn!("The pilot run recorded no hits, keeping only the max iteration");
+    }
+    let cutoffs = spectrum.propose_cutoffs(options.layers, max_iteration);
+    if cutoffs.len() < options.layers {
+        warn!(
+            "Only {} distinct cutoffs could be proposed for {} layers",
+            cutoffs.len(),
+            options.layers
+        );
+    }
+    info!(hits = spectrum.total(), ?cutoffs, "Cutoffs proposed");
+
+    // Settings that are per layer no longer line up with the new cutoffs
+    if cutoffs.len() != config.layer_count() {
+        if let Some(convergence) = &config.convergence {
+            if convergence.target_errors.len() != 1 {
+                warn!("Dropping per-layer convergence targets, they need to be set again");
+                config.convergence = None;
+            }
+        }
+    }
+    config.cutoffs = cutoffs;
+    config.layers = None;
+
+    let writer = std::io::BufWriter::new(std::fs::File::create(&options.output)?);
+    serde_json::to_writer_pretty(writer, &config)?;
+    info!("Config written to {}", options.output.display());
+
+    Ok(())
+}
+
+pub fn run_cutoffs(cutoffs_options: &CutoffsOptions) -> EscapeResult {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(cutoffs_options.workers + 1)
+        .enable_all()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async_cutoffs(cutoffs_options))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spectrum_quantiles() {
+        for len in [1, 2, 3, 17, 100, 1000, 123456] {
+            let bin = OrbitSpectrum::bin(len);
+            assert!(OrbitSpectrum::upper_edge(bin) >= len);
+            assert!(OrbitSpectrum::bin(OrbitSpectrum::upper_edge(bin) + 1) > bin);
+        }
+
+        let mut counts = vec![0; OrbitSpectrum::bin_count(10000)];
+        counts[OrbitSpectrum::bin(10)] = 50;
+        counts[OrbitSpectrum::bin(1000)] = 50;
+        let spectrum = OrbitSpectrum::from_counts(counts);
+        assert_eq!(
+            spectrum.quantile(0.5),
+            Some(OrbitSpectrum::upper_edge(OrbitSpectrum::bin(10)))
+        );
+        assert_eq!(
+            spectrum.quantile(0.9),
+            Some(OrbitSpectrum::upper_edge(OrbitSpectrum::bin(1000)))
+        );
+
+        let cutoffs = spectrum.propose_cutoffs(4, 10000);
+        assert_eq!(cutoffs.len(), 3);
+        assert_eq!(cutoffs[2], 10000);
+        assert!(OrbitSpectrum::from_counts(Vec::new()).propose_cutoffs(3, 500) == vec![500]);
+    }
+}
@@ -0,0 +1,140 @@
+use crate::spectrum::OrbitSpectrum;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Counters a sampling worker updates as it runs
+/// These are only ever read for reporting, so relaxed ordering is enough
+#[derive(Debug)]
+pub struct WorkerStats {
+    pub evaluated: AtomicU64,
+    pub escaped: AtomicU64,
+    pub orbit_iterations: AtomicU64,
+    pub accepted: AtomicU64,
+    pub rejected: AtomicU64,
+    pub outside: AtomicU64,
+    pub metro_instances: AtomicU64,
+    pub metro_failures: AtomicU64,
+    pub initial_sample_failures: AtomicU64,
+
+    /// Cells this worker was the first to record to
+    pub filled_cells: AtomicU64,
+    pub layer_hits: Vec<AtomicU64>,
+
+    /// Hits of recorded orbits, binned by orbit length, see OrbitSpectrum
+    pub spectrum: Vec<AtomicU64>,
+}
+pub type ArcStats = Arc<WorkerStats>;
+
+impl WorkerStats {
+    pub fn new(layers: usize, max_orbit_len: usize) -> ArcStats {
+        Arc::new(WorkerStats {
+            evaluated: AtomicU64::new(0),
+            escaped: AtomicU64::new(0),
+            orbit_iterations: AtomicU64::new(0),
+            accepted: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            outside: AtomicU64::new(0),
+            metro_instances: AtomicU64::new(0),
+            metro_failures: AtomicU64::new(0),
+            initial_sample_failures: AtomicU64::new(0),
+            filled_cells: AtomicU64::new(0),
+            layer_hits: (0..layers).map(|_| AtomicU64::new(0)).collect(),
+            spectrum: (0..OrbitSpectrum::bin_count(max_orbit_len))
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+        })
+    }
+
+    pub fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        StatsSnapshot {
+            evaluated: load(&self.evaluated),
+            escaped: load(&self.escaped),
+            orbit_iterations: load(&self.orbit_iterations),
+            accepted: load(&self.accepted),
+            rejected: load(&self.rejected),
+            outside: load(&self.outside),
+            metro_instances: load(&self.metro_instances),
+            metro_failures: load(&self.metro_failures),
+            initial_sample_failures: load(&self.initial_sample_failures),
+            filled_cells: load(&self.filled_cells),
+            layer_hits: self.layer_hits.iter().map(load).collect(),
+            spectrum: self.spectrum.iter().map(load).collect(),
+        }
+    }
+}
+
+/// The counters of one or more workers at a point in time
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct StatsSnapshot {
+    pub evaluated: u64,
+    pub escaped: u64,
+    pub orbit_iterations: u64,
+    pub accepted: u64,
+    pub rejected: u64,
+    pub outside: u64,
+    pub metro_instances: u64,
+    pub metro_failures: u64,
+    pub initial_sample_failures: u64,
+    pub filled_cells: u64,
+    pub layer_hits: Vec<u64>,
+    pub spectrum: Vec<u64>,
+}
+
+impl StatsSnapshot {
+    /// Sum the counters of a set of workers
+    pub fn total(stats: &[ArcStats]) -> StatsSnapshot {
+        let mut result = StatsSnapshot::default();
+        for worker in stats {
+            result.add(&worker.snapshot());
+        }
+        result
+    }
+
+    pub fn add(&mut self, other: &StatsSnapshot) {
+        self.evaluated += other.evaluated;
+        self.escaped += other.escaped;
+        self.orbit_iterations += other.orbit_iterations;
+        self.accepted += other.accepted;
+        self.rejected += other.rejected;
+        self.outside += other.outside;
+        self.metro_instances += other.metro_instances;
+        self.metro_failures += other.metro_failures;
+        self.initial_sample_failures += other.initial_sample_failures;
+        self.filled_cells += other.filled_cells;
+        if self.layer_hits.len() < other.layer_hits.len() {
+            self.layer_hits.resize(other.layer_hits.len(), 0);
+        }
+        for (hits, other_hits) in self.layer_hits.iter_mut().zip(&other.layer_hits) {
+            *hits += other_hits;
+        }
+        if self.spectrum.len() < other.spectrum.len() {
+            self.spectrum.resize(other.spectrum.len(), 0);
+        }
+        for (count, other_count) in self.spectrum.iter_mut().zip(&other.spectrum) {
+            *count += other_count;
+        }
+    }
+
+    pub fn orbit_spectrum(&self) -> OrbitSpectrum {
+        OrbitSpectrum::from_counts(self.spectrum.clone())
+    }
+
+    /// Every sample proposed by a chain is either accepted, rejected, or outside the view
+    pub fn samples(&self) -> u64 {
+        self.accepted + self.rejected + self.outside
+    }
+
+    pub fn average_orbit_length(&self) -> f64 {
+        if self.evaluated == 0 {
+            0.0
+        } else {
+            self.orbit_iterations as f64 / self.evaluated as f64
+        }
+    }
+}
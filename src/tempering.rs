@@ -0,0 +1,250 @@
+use parking_lot::{Condvar, Mutex};
+use std::sync::Arc;
+
+use crate::config::TemperingConfig;
+use crate::types::Complex;
+
+/// The state of a metropolis hastings chain, as needed to continue it elsewhere
+#[derive(Copy, Clone, Debug)]
+pub struct ChainState {
+    pub sample: Complex,
+    pub orbit_len: usize,
+    pub contrib: f64,
+}
+
+/// A chain waiting at the exchange for its partner to reach the same round
+#[derive(Copy, Clone, Debug)]
+struct Offer {
+    round: usize,
+    partner: usize,
+    state: ChainState,
+    u: f64,
+
+    /// Set by the partner once it has arrived, with the state to continue from if they swapped
+    answer: Option<Option<ChainState>>,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct Slot {
+    offer: Option<Offer>,
+
+    /// The chain has stopped sampling, and won't visit the exchange again
+    retired: bool,
+}
+
+/// Shared by the workers of one ladder, chains use this to swap states.
+///
+/// Chains count the swaps that come due, and a pair of chains only swaps
+/// between the states they were in at the same round.
+/// The first of a pair to arrive waits for the other, or for it to retire,
+/// and the lower rung's draw decides the swap, so a seeded run is reproducible.
+#[derive(Debug)]
+pub struct Exchange {
+    slots: Mutex<Vec<Slot>>,
+    arrived: Condvar,
+    betas: Vec<f64>,
+}
+pub type ArcExchange = Arc<Exchange>;
+
+/// Probability of accepting a swap between two chains with inverse temperatures
+/// beta_a and beta_b, currently in states with the given contributions
+pub fn swap_probability(beta_a: f64, contrib_a: f64, beta_b: f64, contrib_b: f64) -> f64 {
+    ((beta_a - beta_b) * (contrib_b.ln() - contrib_a.ln()))
+        .exp()
+        .clamp(0.0, 1.0)
+}
+
+impl Exchange {
+    pub fn new(config: &TemperingConfig) -> ArcExchange {
+        Arc::new(Exchange {
+            slots: Mutex::new(vec![Slot::default(); config.temperatures.len()]),
+            arrived: Condvar::new(),
+            betas: config.temperatures.iter().map(|t| 1.0 / t).collect(),
+        })
+    }
+
+    /// Visit the exchange with the state of the chain on rung at a round,
+    /// and propose a swap with the chain on partner.
+    /// Returns the partner's state if they swapped
+    fn visit(
+        &self,
+        rung: usize,
+        partner: usize,
+        round: usize,
+        state: ChainState,
+        u: f64,
+    ) -> Option<ChainState> {
+        let mut slots = self.slots.lock();
+        if slots[partner].retired {
+            return None;
+        }
+
+        // The partner is already waiting for us
+        if let Some(offer) = &mut slots[partner].offer {
+            if offer.round == round && offer.partner == rung && offer.answer.is_none() {
+                let u = if rung < partner { u } else { offer.u };
+                let p = swap_probability(
+                    self.betas[rung],
+                    state.contrib,
+                    self.betas[partner],
+                    offer.state.contrib,
+                );
+                let swapped = p > u;
+                offer.answer = Some(swapped.then_some(state));
+                let other = offer.state;
+                self.arrived.notify_all();
+                return swapped.then_some(other);
+            }
+        }
+
+        slots[rung].offer = Some(Offer {
+            round,
+            partner,
+            state,
+            u,
+            answer: None,
+        });
+        loop {
+            if let Some(answer) = slots[rung].offer.and_then(|offer| offer.answer) {
+                slots[rung].offer = None;
+                return answer;
+            }
+            if slots[partner].retired {
+                slots[rung].offer = None;
+                return None;
+            }
+            self.arrived.wait(&mut slots);
+        }
+    }
+
+    /// The chain on rung has stopped, partners waiting for it give up
+    fn retire(&self, rung: usize) {
+        self.slots.lock()[rung].retired = true;
+        self.arrived.notify_all();
+    }
+}
+
+/// A worker's place in a tempering ladder
+#[derive(Debug)]
+pub struct TemperingRung {
+    exchange: ArcExchange,
+    rung: usize,
+    beta: f64,
+    swap_interval: usize,
+    round: usize,
+    pub swaps: usize,
+}
+
+impl TemperingRung {
+    pub fn new(config: &TemperingConfig, exchange: ArcExchange, rung: usize) -> TemperingRung {
+        TemperingRung {
+            exchange,
+            rung,
+            beta: 1.0 / config.temperatures[rung],
+            swap_interval: config.swap_interval,
+            round: 0,
+            swaps: 0,
+        }
+    }
+
+    /// Only the cold chain samples the actual contribution function
+    pub fn is_cold(&self) -> bool {
+        self.rung == 0
+    }
+
+    /// Inverse temperature of this rung
+    pub fn beta(&self) -> f64 {
+        self.beta
+    }
+
+    /// Swaps come due every swap_interval samples the worker takes
+    pub fn swap_due(&self, samples_taken: u64) -> bool {
+        samples_taken.is_multiple_of(self.swap_interval as u64)
+    }
+
+    /// Propose a swap, alternating between the neighbors above and below this rung by round
+    pub fn exchange(&mut self, state: ChainState, u: f64) -> Option<ChainState> {
+        let round = self.round;
+        self.round += 1;
+        let partner = if (self.rung + round).is_multiple_of(2) {
+            Some(self.rung + 1)
+        } else {
+            self.rung.checked_sub(1)
+        };
+
+        let partner = partner.filter(|partner| *partner < self.exchange.betas.len())?;
+        let result = self.exchange.visit(self.rung, partner, round, state, u);
+        if result.is_some() {
+            self.swaps += 1;
+        }
+        result
+    }
+
+    /// Stop taking part in swaps, so the neighbors don't wait for this chain
+    pub fn retire(&self) {
+        self.exchange.retire(self.rung);
+    }
+}
+
+impl Drop for TemperingRung {
+    fn drop(&mut self) {
+        self.retire();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(contrib: f64) -> ChainState {
+        ChainState {
+            sample: Complex::new(contrib, 0.0),
+            orbit_len: 10,
+            contrib,
+        }
+    }
+
+    #[test]
+    fn swap_probabilities() {
+        // Equal temperatures always swap
+        assert_eq!(swap_probability(1.0, 0.1, 1.0, 0.9), 1.0);
+
+        // The cold chain always takes a better state from a hot chain
+        assert_eq!(swap_probability(1.0, 0.1, 0.5, 0.2), 1.0);
+
+        // but only sometimes gives up a better state
+        let p = swap_probability(1.0, 0.2, 0.5, 0.1);
+        assert!((p - 0.5f64.sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn exchange_swaps_states() {
+        let config = TemperingConfig {
+            temperatures: vec![1.0, 2.0],
+            swap_interval: 10,
+        };
+        let exchange = Exchange::new(&config);
+        let mut cold = TemperingRung::new(&config, exchange.clone(), 0);
+        let mut hot = TemperingRung::new(&config, exchange, 1);
+        assert!(cold.swap_due(20));
+        assert!(!cold.swap_due(21));
+
+        // The hot chain waits for the cold chain, and both swap the states they compared
+        let hot = std::thread::spawn(move || {
+            let swapped = hot.exchange(state(0.5), 1.0);
+            (hot, swapped)
+        });
+        let swapped = cold.exchange(state(0.1), 0.0).unwrap();
+        let (hot, hot_swapped) = hot.join().unwrap();
+        assert_eq!(swapped.contrib, 0.5);
+        assert_eq!(hot_swapped.unwrap().contrib, 0.1);
+        assert_eq!(cold.swaps, 1);
+        assert_eq!(hot.swaps, 1);
+
+        // The cold chain has no partner below it, and a retired partner is not waited for
+        assert!(cold.exchange(state(0.1), 0.0).is_none());
+        drop(hot);
+        assert!(cold.exchange(state(0.1), 0.0).is_none());
+        assert_eq!(cold.swaps, 1);
+    }
+}
@@ -0,0 +1,349 @@
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::cli_options::DrawTimelapseOptions;
+use crate::config::{DrawConfig, SampleConfig};
+use crate::draw;
+use crate::grid::HistogramGrid;
+use crate::sample::{ArcSwitch, Sampler};
+use crate::types::{CountGrid, EscapeError, EscapeResult, NormalizedGrid, TiledCountGrid};
+
+/// When time-lapse frames are taken, relative to the start of sampling
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TimelapseSchedule {
+    /// A frame every interval seconds, written as linear:<interval>
+    Linear { interval: f64 },
+
+    /// The first frame after first seconds, then each frame factor times later than the last,
+    /// written as geometric:<first>:<factor>
+    Geometric { first: f64, factor: f64 },
+}
+
+impl TimelapseSchedule {
+    /// Seconds from the start of sampling until frame k
+    pub fn frame_time(&self, k: usize) -> f64 {
+        match self {
+            TimelapseSchedule::Linear { interval } => interval * (k + 1) as f64,
+            TimelapseSchedule::Geometric { first, factor } => first * factor.powi(k as i32),
+        }
+    }
+}
+
+impl std::str::FromStr for TimelapseSchedule {
+    type Err = EscapeError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || EscapeError::ScheduleParse(s.to_string());
+        let parts: Vec<&str> = s.trim().split(':').collect();
+        let number = |part: &str| -> Result<f64, EscapeError> {
+            match part.parse::<f64>() {
+                Ok(value) if value > 0.0 => Ok(value),
+                _ => Err(error()),
+            }
+        };
+        match parts.as_slice() {
+            ["linear", interval] => Ok(TimelapseSchedule::Linear {
+                interval: number(interval)?,
+            }),
+            ["geometric", first, factor] => {
+                let factor = number(factor)?;
+                if factor <= 1.0 {
+                    return Err(error());
+                }
+                Ok(TimelapseSchedule::Geometric {
+                    first: number(first)?,
+                    factor,
+                })
+            }
+            _ => Err(error()),
+        }
+    }
+}
+
+/// The first line of a time-lapse file
+#[derive(Serialize, Deserialize)]
+struct TimelapseHeader {
+    config: SampleConfig,
+}
+
+/// Every following line is a frame, holding only the counts added since the previous frame
+#[derive(Serialize, Deserialize)]
+pub struct TimelapseFrame {
+    pub elapsed_seconds: f64,
+    pub deltas: Vec<TiledCountGrid>,
+}
+
+/// Appends frames to a time-lapse file, as json lines
+pub struct TimelapseWriter {
+    writer: BufWriter<std::fs::File>,
+    previous: Vec<CountGrid>,
+}
+
+impl TimelapseWriter {
+    pub fn create(path: &Path, config: &SampleConfig) -> Result<TimelapseWriter, EscapeError> {
+        let mut writer = BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer(
+            &mut writer,
+            &TimelapseHeader {
+                config: config.clone(),
+            },
+        )?;
+        writeln!(writer)?;
+        writer.flush()?;
+
+        let view = config.view;
+        Ok(TimelapseWriter {
+            writer,
+            previous: vec![CountGrid::zero(view.width, view.height); config.layer_count()],
+        })
+    }
+
+    /// Write the difference between the cumulative grids and the previous frame
+    pub fn write_frame(&mut self, elapsed_seconds: f64, grids: &[HistogramGrid]) -> EscapeResult {
+        let mut deltas = Vec::with_capacity(grids.len());
+        for (grid, previous) in grids.iter().zip(self.previous.iter_mut()) {
+            let current = grid.to_dense();
+            let (width, height) = grid.dimensions();
+            let mut delta = TiledCountGrid::zero(width, height);
+            for y in 0..height {
+                for x in 0..width {
+                    let change = current.value(x, y).saturating_sub(previous.value(x, y));
+                    delta.set_value(change, x, y);
+                }
+            }
+            deltas.push(delta);
+            *previous = current;
+        }
+
+        let frame = TimelapseFrame {
+            elapsed_seconds,
+            deltas,
+        };
+        serde_json::to_writer(&mut self.writer, &frame)?;
+        writeln!(self.writer)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Read a time-lapse file, returning its config and frames
+pub fn read_timelapse(path: &Path) -> Result<(SampleConfig, Vec<TimelapseFrame>), EscapeError> {
+    let mut lines = BufReader::new(std::fs::File::open(path)?).lines();
+    let header: TimelapseHeader = match lines.next() {
+        Some(line) => serde_json::from_str(&line?)?,
+        None => {
+            let msg = format!("{} is empty", path.display());
+            return Err(EscapeError::InvalidTimelapse(msg));
+        }
+    };
+
+    let mut frames = Vec::new();
+    for line in lines {
+        let line = line?;
+        // A run that was killed may have left a partial last line
+        match serde_json::from_str::<TimelapseFrame>(&line) {
+            Ok(frame) => frames.push(frame),
+            Err(e) => {
+                warn!("Ignoring unreadable frame {}: {}", frames.len(), e);
+                break;
+            }
+        }
+    }
+
+    Ok((header.config, frames))
+}
+
+/// Take frames on the schedule until the stop switch is triggered, returning the writer
+/// so that a last frame can be written once the workers have finished
+/// Failed frames are logged, since they shouldn't cost the run
+pub async fn record_timelapse(
+    sampler: &Sampler,
+    mut writer: TimelapseWriter,
+    start: Instant,
+    schedule: TimelapseSchedule,
+    stop_switch: &ArcSwitch,
+) -> Option<TimelapseWriter> {
+    for k in 0.. {
+        let frame_time = start + Duration::from_secs_f64(schedule.frame_time(k));
+        while Instant::now() < frame_time {
+            if stop_switch.read().stop() {
+                return Some(writer);
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        let grids = match sampler.snapshot().await {
+            Ok(Some(grids)) => grids,
+            Ok(None) => return Some(writer),
+            Err(e) => {
+                warn!(frame = k, "Failed to take time-lapse frame: {:?}", e);
+                continue;
+            }
+        };
+        let elapsed = start.elapsed().as_secs_f64();
+        let written = tokio::task::spawn_blocking(move || {
+            let result = writer.write_frame(elapsed, &grids);
+            (writer, result)
+        })
+        .await;
+        match written {
+            Ok((returned, result)) => {
+                writer = returned;
+                match result {
+                    Ok(()) => info!(frame = k, elapsed, "Time-lapse frame written"),
+                    Err(e) => warn!(frame = k, "Failed to write time-lapse frame: {:?}", e),
+                }
+            }
+            Err(e) => {
+                warn!("Time-lapse writer was lost: {:?}", e);
+                return None;
+            }
+        }
+    }
+
+    Some(writer)
+}
+
+/// Normalize a cumulative grid as a density, relative to the brightest density of the final frame
+/// so that brightness stays consistent across frames
+fn normalize_frame(grid: &CountGrid, final_max_density: f64) -> NormalizedGrid {
+    let total = grid.data().iter().sum::<u64>() as f64;
+    let (width, height) = grid.dimensions();
+    let mut result = NormalizedGrid::zero(width, height);
+    if total == 0.0 || final_max_density == 0.0 {
+        return result;
+    }
+
+    for y in 0..height {
+        for x in 0..width {
+            let density = grid.value(x, y) as f64 / total;
+            result.set_value((density / final_max_density).min(1.0), x, y);
+        }
+    }
+    result
+}
+
+pub fn run_draw_timelapse(options: &DrawTimelapseOptions) -> EscapeResult {
+    let logger_builder = tracing_subscriber::fmt()
+        .with_timer(tracing_subscriber::fmt::time::uptime())
+        .with_thread_ids(true)
+        .with_max_level(&options.verbosity);
+    if options.pretty_logging {
+        logger_builder.pretty().init()
+    } else {
+        logger_builder.init();
+    }
+
+    let mut config_reader = BufReader::new(std::fs::File::open(&options.config)?);
+    let draw_config: DrawConfig = serde_json::from_reader(&mut config_reader)?;
+    info!("Loaded draw config {}", &options.config.display());
+
+    let (sample_config, frames) = read_timelapse(&options.timelapse)?;
+    draw_config.compatible(&sample_config)?;
+    info!(frames = frames.len(), "Loaded time-lapse");
+
+    let view = sample_config.view;
+    let layers = sample_config.layer_count();
+    let mut cumulative = vec![CountGrid::zero(view.width, view.height); layers];
+    for frame in &frames {
+        for (grid, delta) in cumulative.iter_mut().zip(&frame.deltas) {
+            delta.add_to(grid);
+        }
+    }
+    let final_max_densities: Vec<f64> = cumulative
+        .iter()
+        .map(|grid| {
+            let total = grid.data().iter().sum::<u64>() as f64;
+            let max = grid.data().iter().copied().max().unwrap_or(0) as f64;
+            if total == 0.0 {
+                0.0
+            } else {
+                max / total
+            }
+        })
+        .collect();
+
+    std::fs::create_dir_all(&options.output_dir)?;
+    let mut cumulative = vec![CountGrid::zero(view.width, view.height); layers];
+    for (index, frame) in frames.iter().enumerate() {
+        for (grid, delta) in cumulative.iter_mut().zip(&frame.deltas) {
+            delta.add_to(grid);
+        }
+        let normalized: Vec<NormalizedGrid> = cumulative
+            .iter()
+            .zip(&final_max_densities)
+            .map(|(grid, max_density)| normalize_frame(grid, *max_density))
+            .collect();
+
+        let image = draw::color_grids(&draw_config, &sample_config, &normalized);
+        let path = options.output_dir.join(format!("frame_{:05}.png", index));
+        image.save(&path)?;
+        info!(
+            elapsed_seconds = frame.elapsed_seconds,
+            "Frame saved to {}",
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schedules() {
+        let linear: TimelapseSchedule = "linear:30".parse().unwrap();
+        assert_eq!(linear.frame_time(0), 30.0);
+        assert_eq!(linear.frame_time(3), 120.0);
+
+        let geometric: TimelapseSchedule = "geometric:10:2".parse().unwrap();
+        assert_eq!(geometric.frame_time(0), 10.0);
+        assert_eq!(geometric.frame_time(3), 80.0);
+
+        assert!("geometric:10:1".parse::<TimelapseSchedule>().is_err());
+        assert!("linear:-1".parse::<TimelapseSchedule>().is_err());
+        assert!("hourly".parse::<TimelapseSchedule>().is_err());
+    }
+
+    #[test]
+    fn frames_are_deltas() {
+        let config: SampleConfig = serde_json::from_str(
+            r#"{
+                "cutoffs": [20],
+                "view": { "width": 3, "height": 2, "zoom": 0.25, "center": [0.0, 0.0] }
+            }"#,
+        )
+        .unwrap();
+        let path = std::env::temp_dir().join(format!("escape_timelapse_{}", std::process::id()));
+
+        let mut grid = CountGrid::zero(3, 2);
+        grid.set_value(2, 1, 1);
+        let mut writer = TimelapseWriter::create(&path, &config).unwrap();
+        writer
+            .write_frame(1.0, &[HistogramGrid::Dense(grid.clone())])
+            .unwrap();
+        grid.set_value(5, 1, 1);
+        grid.set_value(1, 0, 0);
+        writer
+            .write_frame(2.0, &[HistogramGrid::Dense(grid.clone())])
+            .unwrap();
+        drop(writer);
+
+        let (_, frames) = read_timelapse(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].deltas[0].value(1, 1), 2);
+        assert_eq!(frames[1].deltas[0].value(1, 1), 3);
+        assert_eq!(frames[1].deltas[0].value(0, 0), 1);
+
+        let mut cumulative = CountGrid::zero(3, 2);
+        for frame in &frames {
+            frame.deltas[0].add_to(&mut cumulative);
+        }
+        assert_eq!(cumulative.data(), grid.data());
+    }
+}
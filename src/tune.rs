@@ -0,0 +1,288 @@
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::cli_options::TuneOptions;
+use crate::config::SampleConfig;
+use crate::convergence::relative_error;
+use crate::grid::HistogramGrid;
+use crate::memory::GridStrategy;
+use crate::sample::{ArcSwitch, Sampler, StopSwitch, WorkBudget};
+use crate::stats::StatsSnapshot;
+use crate::types::{EscapeError, EscapeResult};
+
+type Setter = fn(&mut SampleConfig, f64);
+
+/// A sampler parameter, and the values tried for it
+struct TunedParameter {
+    name: &'static str,
+    candidates: &'static [f64],
+    set: Setter,
+    get: fn(&SampleConfig) -> f64,
+}
+
+/// Parameters are tuned one at a time, in this order
+const PARAMETERS: [TunedParameter; 6] = [
+    TunedParameter {
+        name: "random_sample_prob",
+        candidates: &[0.01, 0.05, 0.1, 0.2, 0.4],
+        set: |c, v| c.random_sample_prob = v,
+        get: |c| c.random_sample_prob,
+    },
+    TunedParameter {
+        name: "max_mutation_radius",
+        candidates: &[0.01, 0.05, 0.1, 0.3],
+        set: |c, v| c.max_mutation_radius = v,
+        get: |c| c.max_mutation_radius,
+    },
+    TunedParameter {
+        name: "min_mutation_radius",
+        candidates: &[0.00001, 0.0001, 0.001],
+        set: |c, v| c.min_mutation_radius = v,
+        get: |c| c.min_mutation_radius,
+    },
+    TunedParameter {
+        name: "samples",
+        candidates: &[1000.0, 10000.0, 100000.0, 1000000.0],
+        set: |c, v| c.samples = v as usize,
+        get: |c| c.samples as f64,
+    },
+    TunedParameter {
+        name: "warm_up_samples",
+        candidates: &[100.0, 1000.0, 10000.0],
+        set: |c, v| c.warm_up_samples = v as usize,
+        get: |c| c.warm_up_samples as f64,
+    },
+    TunedParameter {
+        name: "outside_limit",
+        candidates: &[20.0, 100.0, 500.0],
+        set: |c, v| c.outside_limit = v as usize,
+        get: |c| c.outside_limit as f64,
+    },
+];
+
+/// What a pilot run measured
+#[derive(Debug, Copy, Clone)]
+struct PilotResult {
+    hits_per_cpu_second: f64,
+
+    /// Mean relative error over the layers, if there were enough workers to estimate it
+    relative_error: Option<f64>,
+
+    cpu_seconds: f64,
+}
+
+impl PilotResult {
+    /// With an error estimate the score is the effective samples per cpu second,
+    /// since the squared relative error falls off with the number of independent samples.
+    /// Otherwise it is in-view hits per cpu second.
+    fn score(&self) -> f64 {
+        match self.relative_error {
+            Some(error) if error.is_finite() && error > 0.0 => {
+                1.0 / (error * error * self.cpu_seconds)
+            }
+            Some(_) => 0.0,
+            None => self.hits_per_cpu_second,
+        }
+    }
+}
+
+async fn pilot_run(
+    config: &SampleConfig,
+    workers: usize,
+    duration: Duration,
+    seed: Option<u64>,
+) -> Result<PilotResult, EscapeError> {
+    let stop_switch = StopSwitch::without_handlers();
+    let sampler = Sampler::start(
+        Arc::new(config.clone()),
+        workers,
+        GridStrategy::Local,
+        seed,
+        WorkBudget::default(),
+        false,
+        stop_switch.clone(),
+    )?;
+
+    let start = Instant::now();
+    tokio::time::sleep(duration).await;
+    let worker_grids = sampler.worker_snapshots().await;
+    let elapsed = start.elapsed().as_secs_f64();
+    let totals = StatsSnapshot::total(&sampler.stats());
+    stop_switch.write().trigger("Pilot run complete");
+    sampler.finish().await?;
+
+    let cpu_seconds = elapsed * workers as f64;
+    let hits: u64 = totals.layer_hits.iter().sum();
+    let relative_error = match worker_grids {
+        Some(worker_grids) if worker_grids.len() >= 2 => {
+            let layers = config.layer_count();
+            let mean_sqr = (0..layers)
+                .map(|layer| {
+                    let grids: Vec<&HistogramGrid> =
+                        worker_grids.iter().map(|grids| &grids[layer]).collect();
+                    relative_error(&grids).unwrap_or(0.0).powi(2)
+                })
+                .sum::<f64>()
+                / layers as f64;
+            Some(mean_sqr.sqrt())
+        }
+        _ => None,
+    };
+
+    Ok(PilotResult {
+        hits_per_cpu_second: hits as f64 / cpu_seconds,
+        relative_error,
+        cpu_seconds,
+    })
+}
+
+/// Tune one parameter at a time, keeping the best value before moving to the next
+/// Returns early with the best config so far if the stop switch is triggered
+async fn tune(
+    mut config: SampleConfig,
+    options: &TuneOptions,
+    stop_switch: &ArcSwitch,
+) -> Result<SampleConfig, EscapeError> {
+    let duration = Duration::from_secs(options.pilot_duration);
+    let mut best = pilot_run(&config, options.workers, duration, options.seed).await?;
+    info!(
+        score = best.score(),
+        hits_per_cpu_second = best.hits_per_cpu_second,
+        relative_error = ?best.relative_error,
+        "Baseline pilot run"
+    );
+
+    for pass in 0..options.passes {
+        for parameter in PARAMETERS.iter() {
+            let current = (parameter.get)(&config);
+            for value in parameter.candidates {
+                if stop_switch.read().stop() {
+                    warn!("Tuning stopped early");
+                    return Ok(config);
+                }
+                if *value == current {
+                    continue;
+                }
+
+                let mut candidate = config.clone();
+                (parameter.set)(&mut candidate, *value);
+                if candidate.validate().is_err() {
+                    continue;
+                }
+
+                let result = pilot_run(&candidate, options.workers, duration, options.seed).await?;
+                info!(
+                    pass,
+                    parameter = parameter.name,
+                    value,
+                    score = result.score(),
+                    hits_per_cpu_second = result.hits_per_cpu_second,
+                    relative_error = ?result.relative_error,
+                    "Pilot run"
+                );
+                if result.score() > best.score() {
+                    best = result;
+                    config = candidate;
+                }
+            }
+            info!(
+                parameter = parameter.name,
+                value = (parameter.get)(&config),
+                "Parameter tuned"
+            );
+        }
+    }
+
+    Ok(config)
+}
+
+async fn async_tune(options: &TuneOptions) -> EscapeResult {
+    let logger_builder = tracing_subscriber::fmt()
+        .with_timer(tracing_subscriber::fmt::time::uptime())
+        .with_thread_ids(true)
+        .with_max_level(&options.verbosity);
+
+    if options.pretty_logging {
+        logger_builder.pretty().init()
+    } else {
+        logger_builder.init();
+    }
+
+    let mut config_reader = BufReader::new(std::fs::File::open(&options.config)?);
+    let config: SampleConfig = serde_json::from_reader(&mut config_reader)?;
+    config.validate()?;
+
+    if options.workers < 2 {
+        warn!("With fewer than 2 workers noise can't be estimated, tuning for in-view hits only");
+    }
+    let runs = 1 + options.passes * PARAMETERS.iter().map(|p| p.candidates.len()).sum::<usize>();
+    info!(
+        "Tuning with up to {} pilot runs of {}s each",
+        runs, options.pilot_duration
+    );
+
+    let stop_switch = StopSwitch::new(&None).await;
+    let tuned = tune(config, options, &stop_switch).await?;
+
+    let writer = std::io::BufWriter::new(std::fs::File::create(&options.output)?);
+    serde_json::to_writer_pretty(writer, &tuned)?;
+    info!("Tuned config written to {}", options.output.display());
+
+    Ok(())
+}
+
+pub fn run_tune(tune_options: &TuneOptions) -> EscapeResult {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(tune_options.workers + 1)
+        .enable_all()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async_tune(tune_options))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn candidates_are_valid() {
+        let config: SampleConfig = serde_json::from_str(
+            r#"{
+                "cutoffs": [20],
+                "view": { "width": 10, "height": 10, "zoom": 0.25, "center": [0.0, 0.0] }
+            }"#,
+        )
+        .unwrap();
+
+        for parameter in PARAMETERS.iter() {
+            for value in parameter.candidates {
+                let mut candidate = config.clone();
+                (parameter.set)(&mut candidate, *value);
+                assert_eq!((parameter.get)(&candidate), *value);
+                assert!(
+                    candidate.validate().is_ok(),
+                    "{} = {}",
+                    parameter.name,
+                    value
+                );
+            }
+        }
+
+        let noisy = PilotResult {
+            hits_per_cpu_second: 1000.0,
+            relative_error: Some(0.2),
+            cpu_seconds: 10.0,
+        };
+        let quiet = PilotResult {
+            hits_per_cpu_second: 10.0,
+            relative_error: Some(0.1),
+            cpu_seconds: 10.0,
+        };
+        assert!(quiet.score() > noisy.score());
+    }
+}
@@ -1,5 +1,6 @@
 pub type Complex = nalgebra::Complex<f64>;
 pub type CountGrid = crate::grid::Grid<u64>;
+pub type TiledCountGrid = crate::grid::TiledGrid<u64>;
 pub type NormalizedGrid = crate::grid::Grid<f64>;
 
 #[derive(Debug, thiserror::Error)]
@@ -19,34 +20,47 @@ pub enum EscapeError {
     #[error("Incompatible stl config")]
     IncompatibleStlConfig(String),
 
+    #[error("Invalid sample config")]
+    InvalidSampleConfig(String),
+
     #[error("Incompatible Histograms")]
     IncompatibleHistograms,
 
     #[error("Couldn't part verbosity")]
     VerbosityParse(String),
 
+    #[error("Couldn't parse memory size")]
+    MemorySizeParse(String),
+
+    #[error("Couldn't parse time-lapse schedule")]
+    ScheduleParse(String),
+
+    #[error("Invalid time-lapse file")]
+    InvalidTimelapse(String),
+
+    #[error("Memory budget exceeded")]
+    MemoryBudget(String),
+
+    #[error("HTTP error")]
+    Http(String),
+
     #[error("Tokio join error")]
     JoinError(#[from] tokio::task::JoinError),
 }
 
 pub type EscapeResult = Result<(), EscapeError>;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub enum Verbosity {
     Off,
     Error,
     Warn,
+    #[default]
     Info,
     Debug,
     Trace,
 }
 
-impl std::default::Default for Verbosity {
-    fn default() -> Self {
-        Verbosity::Info
-    }
-}
-
 impl std::str::FromStr for Verbosity {
     type Err = EscapeError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -62,6 +76,37 @@ impl std::str::FromStr for Verbosity {
     }
 }
 
+/// A number of bytes, parsed from strings like "512M" or "16G"
+#[derive(Debug, Copy, Clone)]
+pub struct MemorySize(pub u64);
+
+impl std::str::FromStr for MemorySize {
+    type Err = EscapeError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let trimmed = s.trim();
+        let split = trimmed
+            .find(|c: char| c.is_ascii_alphabetic())
+            .unwrap_or(trimmed.len());
+        let (number, unit) = trimmed.split_at(split);
+        let scale: u64 = match unit.to_ascii_lowercase().as_str() {
+            "" | "b" => 1,
+            "k" | "kb" | "kib" => 1 << 10,
+            "m" | "mb" | "mib" => 1 << 20,
+            "g" | "gb" | "gib" => 1 << 30,
+            "t" | "tb" | "tib" => 1 << 40,
+            _ => return Err(EscapeError::MemorySizeParse(s.to_string())),
+        };
+        let value: f64 = number
+            .trim()
+            .parse()
+            .map_err(|_| EscapeError::MemorySizeParse(s.to_string()))?;
+        if !value.is_finite() || value < 0.0 {
+            return Err(EscapeError::MemorySizeParse(s.to_string()));
+        }
+        Ok(MemorySize((value * scale as f64) as u64))
+    }
+}
+
 use tracing_subscriber::filter::LevelFilter;
 impl From<&Verbosity> for LevelFilter {
     fn from(v: &Verbosity) -> LevelFilter {
@@ -75,3 +120,21 @@ impl From<&Verbosity> for LevelFilter {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_sizes() {
+        assert_eq!("1024".parse::<MemorySize>().unwrap().0, 1024);
+        assert_eq!("2K".parse::<MemorySize>().unwrap().0, 2048);
+        assert_eq!("3MB".parse::<MemorySize>().unwrap().0, 3 << 20);
+        assert_eq!("1.5g".parse::<MemorySize>().unwrap().0, 3 << 29);
+        assert!("lots".parse::<MemorySize>().is_err());
+        assert!("NaN".parse::<MemorySize>().is_err());
+        assert!("nan G".parse::<MemorySize>().is_err());
+        assert!("inf".parse::<MemorySize>().is_err());
+        assert!("-1G".parse::<MemorySize>().is_err());
+    }
+}